@@ -3,12 +3,80 @@
 #include <stdlib.h>
 #include <string.h>
 #include <sys/types.h>
+#include <sys/sysmacros.h>
+#include <sys/xattr.h>
+#include <fcntl.h>
+#include <unistd.h>
 #define FUSE_USE_VERSION 26
 #include <fuse.h>
 #include <errno.h>
+#include <pthread.h>
+#include <time.h>
 #include "rust.h"
 
 
+/* Ends the operation's part of the journal transaction. A checksum that
+ * failed or a commit that couldn't be written turns its result into EIO. */
+static int io_result(struct FileSystem* fs, int res)
+{
+    if (rs_end_op(fs))
+        res = -EIO;
+    return rs_take_io_error(fs) ? -EIO : res;
+}
+
+/* The caller as the access checks take it, supplementary groups included.
+ * `groups` has to be freed once the check is done. */
+static struct cred_t caller(gid_t** groups)
+{
+    struct fuse_context *ctx = fuse_get_context();
+    int n = fuse_getgroups(0, NULL);
+    *groups = n > 0 ? calloc(n, sizeof(gid_t)) : NULL;
+    if (!*groups || fuse_getgroups(n, *groups) != n)
+        n = 0;
+    struct cred_t cred = { ctx->uid, ctx->gid, (const uint32_t*) *groups, n };
+    return cred;
+}
+
+static int may_access(struct FileSystem* fs, const char* path, int mask)
+{
+    gid_t* groups;
+    struct cred_t cred = caller(&groups);
+    int res = rs_access(fs, path, mask, &cred);
+    free(groups);
+    return res;
+}
+
+/* Adding or removing `path` needs write and search permission on the
+ * directory it is in. */
+static int may_change_dir(struct FileSystem* fs, const char* path)
+{
+    gid_t* groups;
+    struct cred_t cred = caller(&groups);
+    int res = rs_access_parent(fs, path, W_OK | X_OK, &cred);
+    free(groups);
+    return res;
+}
+
+static int may_own(struct FileSystem* fs, const char* path, uid_t uid, gid_t gid)
+{
+    gid_t* groups;
+    struct cred_t cred = caller(&groups);
+    int res = rs_check_owner(fs, path, uid, gid, &cred);
+    free(groups);
+    return res;
+}
+
+/* As in Linux, ACLs are the owner's to change and trusted attributes
+ * root's, the rest needs write permission. */
+static int may_set_xattr(struct FileSystem* fs, const char* path, const char* name)
+{
+    if (!strncmp(name, "system.posix_acl_", 17))
+        return may_own(fs, path, -1, -1);
+    if (!strncmp(name, "trusted.", 8))
+        return fuse_get_context()->uid ? -EPERM : 0;
+    return may_access(fs, path, W_OK);
+}
+
 int c_getattr(const char* path, struct stat* stbuf)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
@@ -26,106 +94,291 @@ int c_getattr(const char* path, struct stat* stbuf)
     		stbuf->st_mtime = node.mod_time;
     		stbuf->st_ctime = node.creat_time;
     		stbuf->st_size = node.size;
-    		stbuf->st_blocks = 2;
-    		return 0;
+    		if (S_ISCHR(node.type_perm) || S_ISBLK(node.type_perm))
+    		    stbuf->st_rdev = makedev((node.rdev >> 8) & 0xfff,
+    		                             (node.rdev & 0xff) | ((node.rdev >> 12) & 0xfff00));
+    		stbuf->st_blocks = rs_getblocks(fs, path);
+    		return io_result(fs, 0);
     }
-    return -ENOENT;
+    return io_result(fs, -ENOENT);
 }
 
 int c_open(const char* path, struct fuse_file_info* fi)
+{
+    struct fuse_context *ctx = fuse_get_context();
+    struct FileSystem *fs = (struct FileSystem*) ctx->private_data;
+    int64_t fh = rs_open(fs, path);
+    if (fh < 0)
+        return io_result(fs, -ENOENT);
+
+    int mask = 0;
+    if ((fi->flags & O_ACCMODE) != O_WRONLY)
+        mask |= R_OK;
+    if ((fi->flags & O_ACCMODE) != O_RDONLY || (fi->flags & O_TRUNC))
+        mask |= W_OK;
+    int res = may_access(fs, path, mask);
+    if (res)
+        return io_result(fs, res);
+    fi->fh = fh;
+    return io_result(fs, 0);
+}
+
+int c_access(const char* path, int mask)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    return rs_open(fs, path) ? -ENOENT : 0;
+    return io_result(fs, may_access(fs, path, mask));
 }
 
 int c_read(const char* path, char* buf, size_t size, off_t offset, struct fuse_file_info* fi)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_read(fs, path, buf, size, offset);
-    if (res == -1)
-        return -EFAULT;
-    return res ? res : -ENOENT;
+    int res = rs_read_fh(fs, fi->fh, (int8_t*) buf, size, offset);
+    return io_result(fs, res == -1 ? -EBADF : res);
 }
 
 int c_readdir(const char *path, void *buf, fuse_fill_dir_t filler,
 			 off_t offset, struct fuse_file_info *fi)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_access(fs, path, R_OK);
+    if (res)
+        return io_result(fs, res);
     if (rs_readdir(fs, path, buf, filler) == 0)
     {
-        return 0;
+        return io_result(fs, 0);
     }
-    return -ENOENT;
+    return io_result(fs, -ENOENT);
 }
 
 int c_create(const char* path, mode_t mode, struct fuse_file_info* fi)
 {
-    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    if (rs_create(fs, path, mode) == 0)
-        return 0;
-    
-    return -EPERM;
+    struct fuse_context *ctx = fuse_get_context();
+    struct FileSystem *fs = (struct FileSystem*) ctx->private_data;
+    int res = may_change_dir(fs, path);
+    if (res == 0)
+        res = rs_create(fs, path, mode, ctx->uid, ctx->gid);
+    if (res == 0)
+        fi->fh = rs_open(fs, path);
+    return io_result(fs, res);
+}
+
+int c_mknod(const char* path, mode_t mode, dev_t rdev)
+{
+    struct fuse_context *ctx = fuse_get_context();
+    struct FileSystem *fs = (struct FileSystem*) ctx->private_data;
+    int res = may_change_dir(fs, path);
+    if (res == 0)
+        res = rs_mknod(fs, path, mode, rdev, ctx->uid, ctx->gid);
+    return io_result(fs, res);
 }
 
 int c_write(const char* path, const char* buf, size_t size, off_t off, struct fuse_file_info* fi)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int ret = rs_write(fs, path, buf, size, off);
-    if (ret == -1)
-        return -EFBIG;
-    return ret ? ret : -ENODATA;
+    return io_result(fs, rs_write_fh(fs, fi->fh, buf, size, off));
 }
 
-int c_utimens()
+int c_utimens(const char* path, const struct timespec tv[2])
 {
-    return 0;
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int64_t times[2];
+    int given = 0;
+    for (int i = 0; i < 2; i++)
+    {
+        times[i] = tv[i].tv_nsec == UTIME_OMIT ? -1
+                 : tv[i].tv_nsec == UTIME_NOW ? -2 : tv[i].tv_sec;
+        given |= times[i] >= 0;
+    }
+    /* As in Linux, times are the owner's to set, but whoever may write the
+     * file may also set them to the current time. */
+    int res = may_own(fs, path, -1, -1);
+    if (res == -EPERM && !given)
+        res = may_access(fs, path, W_OK);
+    if (res == 0)
+        res = rs_utimens(fs, path, times[0], times[1]);
+    return io_result(fs, res);
 }
 
 int c_truncate(const char* path, off_t size)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int ret = rs_truncate(fs, path, size);
-    return ret ? -ENOENT : 0;
+    int res = may_access(fs, path, W_OK);
+    if (res == 0)
+        res = rs_truncate(fs, path, size);
+    return io_result(fs, res);
 }
 
-int c_chown()
+int c_ftruncate(const char* path, off_t size, struct fuse_file_info* fi)
 {
-    return 0;
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    return io_result(fs, rs_truncate_fh(fs, fi->fh, size));
+}
+
+int c_statfs(const char* path, struct statvfs* stbuf)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    struct statfs_t st;
+    rs_statfs(fs, &st);
+    memset(stbuf, 0, sizeof(*stbuf));
+    stbuf->f_bsize = st.block_size;
+    stbuf->f_frsize = st.block_size;
+    stbuf->f_blocks = st.blocks_num;
+    stbuf->f_bfree = st.free_blocks_num;
+    stbuf->f_bavail = st.free_blocks_num;
+    stbuf->f_files = st.inodes_num;
+    stbuf->f_ffree = st.free_inodes_num;
+    stbuf->f_favail = st.free_inodes_num;
+    stbuf->f_namemax = st.name_max;
+    return io_result(fs, 0);
+}
+
+int c_fsync(const char* path, int datasync, struct fuse_file_info* fi)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    return io_result(fs, rs_fsync(fs, fi->fh) ? -EIO : 0);
+}
+
+int c_fallocate(const char* path, int mode, off_t off, off_t len, struct fuse_file_info* fi)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    if (off < 0 || len <= 0)
+        return io_result(fs, -EINVAL);
+    return io_result(fs, rs_fallocate(fs, path, mode, off, len));
+}
+
+int c_chown(const char* path, uid_t uid, gid_t gid)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_own(fs, path, uid, gid);
+    if (res == 0)
+        res = rs_chown(fs, path, uid, gid);
+    return io_result(fs, res);
 }
 
 int c_rename(const char* from, const char* to)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_rename(fs, from, to);
-    return res ? -ENOENT : 0;
+    int res = may_change_dir(fs, from);
+    if (res == 0)
+        res = may_change_dir(fs, to);
+    if (res == 0)
+        res = rs_rename(fs, from, to);
+    return io_result(fs, res);
 }
 
-int c_mkdir(const char *path, mode_t mode)
+int c_link(const char* from, const char* to)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_access(fs, from, 0);
+    if (res == 0)
+        res = may_change_dir(fs, to);
+    if (res == 0)
+        res = rs_link(fs, from, to);
+    return io_result(fs, res);
+}
+
+int c_symlink(const char* target, const char* linkpath)
+{
+    struct fuse_context *ctx = fuse_get_context();
+    struct FileSystem *fs = (struct FileSystem*) ctx->private_data;
+    int res = may_change_dir(fs, linkpath);
+    if (res == 0)
+        res = rs_symlink(fs, target, linkpath, ctx->uid, ctx->gid);
+    return io_result(fs, res);
+}
+
+int c_readlink(const char* path, char* buf, size_t size)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_mkdir(fs, path);
-    return res ? -EEXIST : 0;
+    int res = may_access(fs, path, 0);
+    if (res)
+        return io_result(fs, res);
+    res = rs_readlink(fs, path, buf, size);
+    return io_result(fs, res ? -EINVAL : 0);
+}
+
+int c_mkdir(const char *path, mode_t mode)
+{
+    struct fuse_context *ctx = fuse_get_context();
+    struct FileSystem *fs = (struct FileSystem*) ctx->private_data;
+    int res = may_change_dir(fs, path);
+    if (res == 0)
+        res = rs_mkdir(fs, path, ctx->uid, ctx->gid);
+    return io_result(fs, res);
 }
 
 int c_unlink(const char* path)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_unlink(fs, path);
-    return res ? -ENOENT : 0;
+    int res = may_change_dir(fs, path);
+    if (res == 0)
+        res = rs_unlink(fs, path);
+    return io_result(fs, res);
 }
 
 int c_rmdir(const char* path)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_rmdir(fs, path);
-    return res ? -ENOENT : 0;
+    int res = may_change_dir(fs, path);
+    if (res == 0)
+        res = rs_rmdir(fs, path);
+    return io_result(fs, res);
 }
 
 int c_chmod(const char* path, mode_t mode)
 {
     struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
-    int res = rs_chmod(fs, path, mode);
-    return res ? -ENOENT : 0;
+    int res = may_own(fs, path, -1, -1);
+    if (res == 0)
+        res = rs_chmod(fs, path, mode);
+    return io_result(fs, res);
+}
+
+int c_getxattr(const char* path, const char* name, char* value, size_t size)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_access(fs, path, 0);
+    if (res)
+        return io_result(fs, res);
+    res = rs_getxattr(fs, path, name, value, size);
+    if (size && res > 0 && (size_t)res > size)
+        return io_result(fs, -ERANGE);
+    return io_result(fs, res);
+}
+
+int c_setxattr(const char* path, const char* name, const char* value, size_t size, int flags)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_set_xattr(fs, path, name);
+    if (res)
+        return io_result(fs, res);
+    int exists = rs_getxattr(fs, path, name, NULL, 0) >= 0;
+    if ((flags & XATTR_CREATE) && exists)
+        return io_result(fs, -EEXIST);
+    if ((flags & XATTR_REPLACE) && !exists)
+        return io_result(fs, -ENODATA);
+    return io_result(fs, rs_setxattr(fs, path, name, value, size));
+}
+
+int c_listxattr(const char* path, char* list, size_t size)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_access(fs, path, 0);
+    if (res)
+        return io_result(fs, res);
+    res = rs_listxattr(fs, path, list, size);
+    if (size && res > 0 && (size_t)res > size)
+        return io_result(fs, -ERANGE);
+    return io_result(fs, res);
+}
+
+int c_removexattr(const char* path, const char* name)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    int res = may_set_xattr(fs, path, name);
+    if (res == 0)
+        res = rs_removexattr(fs, path, name);
+    return io_result(fs, res);
 }
 
 int c_release(const char * path, struct fuse_file_info* fi)
@@ -133,22 +386,105 @@ int c_release(const char * path, struct fuse_file_info* fi)
     return 0;
 }
 
+/* Commits the running transaction once it is due even when no operation
+ * comes along to do it, so an idle mount loses at most that much work. */
+static pthread_t committer;
+static int committer_running;
+static pthread_mutex_t committer_lock = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t committer_stop = PTHREAD_COND_INITIALIZER;
+
+static void* commit_loop(void* data)
+{
+    struct FileSystem *fs = (struct FileSystem*) data;
+    pthread_mutex_lock(&committer_lock);
+    while (committer_running)
+    {
+        int64_t ms = rs_commit_timer(fs);
+        if (ms < 0)
+        {
+            fprintf(stderr, "failed to commit to the image\n");
+            ms = 1000;
+        }
+        struct timespec until;
+        clock_gettime(CLOCK_REALTIME, &until);
+        until.tv_sec += ms / 1000;
+        until.tv_nsec += ms % 1000 * 1000000;
+        if (until.tv_nsec >= 1000000000)
+        {
+            until.tv_sec++;
+            until.tv_nsec -= 1000000000;
+        }
+        pthread_cond_timedwait(&committer_stop, &committer_lock, &until);
+    }
+    pthread_mutex_unlock(&committer_lock);
+    return NULL;
+}
+
+/* Runs once FUSE went into the background, a thread started before that
+ * wouldn't survive the fork. */
+void* c_init(struct fuse_conn_info* conn)
+{
+    struct FileSystem *fs = (struct FileSystem*) fuse_get_context()->private_data;
+    committer_running = 1;
+    if (pthread_create(&committer, NULL, commit_loop, fs))
+    {
+        fprintf(stderr, "can't start the commit thread\n");
+        committer_running = 0;
+    }
+    return fs;
+}
+
+void c_destroy(void* private_data)
+{
+    struct FileSystem *fs = (struct FileSystem*) private_data;
+    pthread_mutex_lock(&committer_lock);
+    int running = committer_running;
+    committer_running = 0;
+    pthread_cond_signal(&committer_stop);
+    pthread_mutex_unlock(&committer_lock);
+    if (running)
+        pthread_join(committer, NULL);
+    struct cache_stats_t stats;
+    rs_cache_stats(fs, &stats);
+    fprintf(stderr, "dentry cache: %llu hits, %llu negative hits, %llu misses\n",
+            stats.dentry_hits, stats.dentry_negative_hits, stats.dentry_misses);
+    fprintf(stderr, "inode cache: %llu hits, %llu misses\n",
+            stats.inode_hits, stats.inode_misses);
+    if (rs_unmount(fs))
+        fprintf(stderr, "failed to sync image on unmount\n");
+}
+
 static struct fuse_operations my_oper = {
     .getattr = c_getattr,
     .open = c_open,
+    .access = c_access,
     .read = c_read,
     .readdir = c_readdir,
     .create = c_create,
+    .mknod = c_mknod,
     .write = c_write,
     .utimens = c_utimens,
     .truncate = c_truncate,
+    .ftruncate = c_ftruncate,
+    .fsync = c_fsync,
+    .statfs = c_statfs,
+    .fallocate = c_fallocate,
     .chown = c_chown,
     .mkdir = c_mkdir,
     .unlink = c_unlink,
     .rmdir = c_rmdir,
     .rename = c_rename,
+    .link = c_link,
+    .symlink = c_symlink,
+    .readlink = c_readlink,
     .chmod = c_chmod,
     .release = c_release,
+    .init = c_init,
+    .destroy = c_destroy,
+    .getxattr = c_getxattr,
+    .setxattr = c_setxattr,
+    .listxattr = c_listxattr,
+    .removexattr = c_removexattr,
 };
 
 void print_version()
@@ -164,8 +500,9 @@ void print_usage()
            "  -h\t\tshow this help message and quit\n"
            "\n"
            "Commands:\n"
-           "  format <block size> <block num> <inode num>\tcreates image with given name\n" 
-           "  mount <fuse args>\t\t\t\tmounts the filesystem\n");
+           "  format <block size> <block num> <inode num> [label]\tcreates image with given name\n" 
+           "  mount <fuse args>\t\t\t\tmounts the filesystem\n"
+           "  fsck\t\t\t\t\t\tchecks the image for inconsistencies\n");
     
 }
 
@@ -177,12 +514,41 @@ int format(int argc, char* argv[])
         return 1;
     }
     FileSystem* fs = rs_init_and_format(argv[1], atoll(argv[3]), atoll(argv[4]), atoll(argv[5]));
+    if (!fs)
+        return 1;
+    if (argc > 6 && rs_set_label(fs, argv[6]))
+    {
+        fprintf(stderr, "label must be at most 16 bytes\n");
+        return 1;
+    }
+    if (rs_unmount(fs))
+    {
+        fprintf(stderr, "failed to write image\n");
+        return 1;
+    }
+    return 0;
+}
+
+// exit codes as with fsck(8): 4 for problems left, 8 if it couldn't check
+int check(char* image)
+{
+    int problems = rs_fsck(image);
+    if (problems < 0)
+        return 8;
+    if (problems > 0)
+    {
+        printf("%s: %d problems found\n", image, problems);
+        return 4;
+    }
+    printf("%s: clean\n", image);
     return 0;
 }
 
 int my_mount(int argc, char** argv)
 {
     FileSystem* fs = rs_init(argv[1]);
+    if (!fs)
+        return 1;
     argv[2] = argv[0];
     char** lol = &argv[2];
     return fuse_main(argc-2, lol, &my_oper, fs);
@@ -221,6 +587,10 @@ int main(int argc, char *argv[])
     {
         return my_mount(argc, argv);
     }
+    if (strcmp(argv[2], "fsck") == 0)
+    {
+        return check(argv[1]);
+    }
     print_usage();
     return 1;
     
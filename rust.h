@@ -11,9 +11,14 @@ typedef struct inode_t {
   unsigned short type_perm;
   unsigned short uid;
   unsigned short gid;
-  unsigned short pad1;
-  unsigned int size;
-  unsigned int pad2;
+  /**
+   * `INODE_*` flags.
+   */
+  unsigned short flags;
+  /**
+   * Low word at the offset of the old 32-bit size, high word in what was `pad2`.
+   */
+  unsigned long long size;
   unsigned long long access_time;
   unsigned long long mod_time;
   unsigned long long creat_time;
@@ -22,43 +27,313 @@ typedef struct inode_t {
   block_p sin_inblock;
   block_p dob_inblock;
   block_p tri_inblock;
-  char unused[24];
+  /**
+   * Device number of character and block devices, packed like Linux
+   * does with 12 bits of major and 20 of minor; root block of the hash
+   * index of directories with `INODE_INDEXED` set.
+   */
+  unsigned int rdev;
+  block_p xattr_block;
+  /**
+   * `XATTR_INLINE_SIZE` bytes of attributes that skip the xattr block.
+   */
+  char xattr_inline[12];
+  /**
+   * CRC-32C of the inode number and the bytes above, with
+   * `FEATURE_METADATA_CSUM`.
+   */
+  unsigned int checksum;
 } inode_t;
 
+/**
+ * Hit and miss counters of the dentry and inode caches.
+ */
+typedef struct cache_stats_t {
+  unsigned long long dentry_hits;
+  /**
+   * Hits on names cached as not existing.
+   */
+  unsigned long long dentry_negative_hits;
+  unsigned long long dentry_misses;
+  unsigned long long inode_hits;
+  unsigned long long inode_misses;
+} cache_stats_t;
+
+/**
+ * What `df` needs to know about the filesystem.
+ */
+typedef struct statfs_t {
+  unsigned int block_size;
+  unsigned int blocks_num;
+  unsigned int free_blocks_num;
+  unsigned int inodes_num;
+  unsigned int free_inodes_num;
+  /**
+   * Longest file name a directory entry takes.
+   */
+  unsigned int name_max;
+} statfs_t;
+
+/**
+ * Identity of the calling process for the access checks, with its
+ * supplementary groups.
+ */
+typedef struct cred_t {
+  uint32_t uid;
+  uint32_t gid;
+  const uint32_t *groups;
+  uintptr_t ngroups;
+} cred_t;
+
 int32_t rs_getattr(struct FileSystem *fs, const char *filename, struct inode_t *inode_buf);
 
-int32_t rs_open(struct FileSystem *fs, const char *filename);
+void rs_cache_stats(struct FileSystem *fs, struct cache_stats_t *stats_buf);
+
+/**
+ * Commits the running transaction when it is due. Returns -1 if writing it
+ * to the image failed.
+ */
+int32_t rs_end_op(struct FileSystem *fs);
+
+/**
+ * Commits the running transaction when it is due, for a timer to call on
+ * an idle mount. Returns the milliseconds until the next one can be, or -1
+ * if writing it to the image failed.
+ */
+int64_t rs_commit_timer(struct FileSystem *fs);
+
+/**
+ * Returns 1 if a checksum failed since the last call, which also marks the
+ * image as having errors, else 0.
+ */
+int32_t rs_take_io_error(struct FileSystem *fs);
+
+void rs_statfs(struct FileSystem *fs, struct statfs_t *statfs_buf);
+
+/**
+ * Returns the number of 512-byte sectors allocated to the file, or -1.
+ */
+int64_t rs_getblocks(struct FileSystem *fs, const char *filename);
 
+/**
+ * Returns the inode number to use as the file handle, or -1.
+ */
+int64_t rs_open(struct FileSystem *fs, const char *filename);
+
+/**
+ * Returns the number of bytes read, 0 at the end of the file or -1 if it doesn't exist.
+ */
 int32_t rs_read(struct FileSystem *fs,
                 const char *filename,
                 int8_t *buf,
                 uintptr_t size,
                 uintptr_t offset);
 
+/**
+ * Same as `rs_read` for a handle from `rs_open`; -1 if the handle is stale.
+ */
+int32_t rs_read_fh(struct FileSystem *fs, uint64_t fh, int8_t *buf, uintptr_t size, uintptr_t offset);
+
 int32_t rs_readdir(struct FileSystem *fs, const char *filename, void *buf, fuse_fill_dir_t filler);
 
-int32_t rs_create(struct FileSystem *fs, const char *filename, uint32_t mode);
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_create(struct FileSystem *fs,
+                  const char *filename,
+                  uint32_t mode,
+                  uint32_t uid,
+                  uint32_t gid);
+
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_mknod(struct FileSystem *fs,
+                 const char *filename,
+                 uint32_t mode,
+                 uint64_t rdev,
+                 uint32_t uid,
+                 uint32_t gid);
 
+/**
+ * Returns the number of bytes written or the negated errno.
+ */
 int32_t rs_write(struct FileSystem *fs,
                  const char *filename,
                  const char *content,
                  uintptr_t size,
                  uintptr_t offset);
 
-int32_t rs_mkdir(struct FileSystem *fs, const char *filename);
+/**
+ * Same as `rs_write` for a handle from `rs_open`.
+ */
+int32_t rs_write_fh(struct FileSystem *fs,
+                    uint64_t fh,
+                    const char *content,
+                    uintptr_t size,
+                    uintptr_t offset);
 
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_mkdir(struct FileSystem *fs, const char *filename, uint32_t uid, uint32_t gid);
+
+/**
+ * Returns the negated errno on failure.
+ */
 int32_t rs_unlink(struct FileSystem *fs, const char *filename);
 
+/**
+ * Returns the negated errno on failure.
+ */
 int32_t rs_rmdir(struct FileSystem *fs, const char *filename);
 
+/**
+ * Returns the negated errno on failure.
+ */
 int32_t rs_truncate(struct FileSystem *fs, const char *filename, uintptr_t size);
 
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_truncate_fh(struct FileSystem *fs, uint64_t fh, uintptr_t size);
+
+int32_t rs_fsync(struct FileSystem *fs, uint64_t fh);
+
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_fallocate(struct FileSystem *fs,
+                     const char *filename,
+                     uint32_t mode,
+                     uintptr_t offset,
+                     uintptr_t len);
+
+/**
+ * Returns the negated errno on failure.
+ */
 int32_t rs_rename(struct FileSystem *fs, const char *from, const char *to);
 
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_link(struct FileSystem *fs, const char *from, const char *to);
+
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_symlink(struct FileSystem *fs,
+                   const char *target,
+                   const char *linkpath,
+                   uint32_t uid,
+                   uint32_t gid);
+
+int32_t rs_readlink(struct FileSystem *fs, const char *filename, char *buf, uintptr_t size);
+
+/**
+ * Returns the negated errno on failure.
+ */
 int32_t rs_chmod(struct FileSystem *fs, const char *filename, uint32_t mode);
 
+/**
+ * Returns the negated errno on failure.
+ */
+int32_t rs_chown(struct FileSystem *fs, const char *filename, uint32_t uid, uint32_t gid);
+
+/**
+ * Returns the value length; the value is only copied when it fits in `size`.
+ * Returns the negated errno on failure, ENODATA when there is no such
+ * attribute.
+ */
+int32_t rs_getxattr(struct FileSystem *fs,
+                    const char *filename,
+                    const char *name,
+                    char *buf,
+                    uintptr_t size);
+
+/**
+ * Returns the negated errno on failure, ENOSPC when the attribute set no
+ * longer fits.
+ */
+int32_t rs_setxattr(struct FileSystem *fs,
+                    const char *filename,
+                    const char *name,
+                    const char *value,
+                    uintptr_t size);
+
+/**
+ * Returns the list length; the list is only copied when it fits in `size`.
+ * Returns the negated errno on failure.
+ */
+int32_t rs_listxattr(struct FileSystem *fs, const char *filename, char *buf, uintptr_t size);
+
+/**
+ * Returns the negated errno on failure, ENODATA when there is no such
+ * attribute.
+ */
+int32_t rs_removexattr(struct FileSystem *fs, const char *filename, const char *name);
+
+/**
+ * Checks `mask` (R_OK, W_OK, X_OK) on the file, and search permission on
+ * the directories leading to it. Returns the negated errno on failure.
+ */
+int32_t rs_access(struct FileSystem *fs,
+                  const char *filename,
+                  uint32_t mask,
+                  const struct cred_t *cred);
+
+/**
+ * `rs_access` for the directory the file is in, as needed to create or
+ * remove it.
+ */
+int32_t rs_access_parent(struct FileSystem *fs,
+                         const char *filename,
+                         uint32_t mask,
+                         const struct cred_t *cred);
+
+/**
+ * Returns -EPERM unless the caller is root or owns the file and, when
+ * `uid` or `gid` isn't -1, may hand it to them.
+ */
+int32_t rs_check_owner(struct FileSystem *fs,
+                       const char *filename,
+                       uint32_t uid,
+                       uint32_t gid,
+                       const struct cred_t *cred);
+
+/**
+ * Takes -1 for a time to leave alone (UTIME_OMIT) and -2 for the current
+ * time (UTIME_NOW). Returns the negated errno on failure.
+ */
+int32_t rs_utimens(struct FileSystem *fs, const char *filename, int64_t atime, int64_t mtime);
+
+/**
+ * Returns NULL, after printing why, when the image can't be opened or isn't
+ * one this driver can use.
+ */
 struct FileSystem *rs_init(const char *filename);
 
+/**
+ * Checks the image without mounting it, after replaying its journal, and
+ * prints what is wrong. An image that is mounted is only read. Returns the
+ * number of problems found, or -1, after printing why, when the image
+ * can't be opened or written.
+ */
+int32_t rs_fsck(const char *filename);
+
+/**
+ * Marks the image clean and commits it. Returns -1 if writing it fails.
+ */
+int32_t rs_unmount(struct FileSystem *fs);
+
+/**
+ * Returns -1 when the label is longer than 16 bytes.
+ */
+int32_t rs_set_label(struct FileSystem *fs, const char *label);
+
+/**
+ * Returns NULL when the image can't be laid out with the given geometry.
+ */
 struct FileSystem *rs_init_and_format(const char *filename,
                                       uint64_t block_size,
                                       uint64_t block_num,
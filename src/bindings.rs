@@ -1,13 +1,24 @@
 use core::slice;
 use std::{
     ffi::{CStr, CString},
-    fs::OpenOptions,
+    fs::{OpenOptions, TryLockError},
+    os::unix::fs::MetadataExt,
+    ptr,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 
-use memmap2::MmapMut;
+use memmap2::MmapOptions;
 
 use crate::types::*;
 
+/// Held for every call on a file system. FUSE calls in from several threads
+/// and its caches and running transaction are for one caller at a time.
+static OP_LOCK: Mutex<()> = Mutex::new(());
+
+fn op_lock() -> MutexGuard<'static, ()> {
+    OP_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 #[allow(non_camel_case_types)]
 /// cbindgen:no-export
 type fuse_fill_dir_t = unsafe extern "C" fn(
@@ -23,6 +34,7 @@ pub unsafe extern "C" fn rs_getattr(
     filename: *const ::std::os::raw::c_char,
     inode_buf: *mut inode_t,
 ) -> i32 {
+    let _op = op_lock();
     //(*fs).test();
     if let Some(inode) = (*fs).get_attr(CStr::from_ptr(filename)) {
         *inode_buf = inode;
@@ -32,16 +44,74 @@ pub unsafe extern "C" fn rs_getattr(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rs_open(
+pub unsafe extern "C" fn rs_cache_stats(fs: *mut FileSystem, stats_buf: *mut cache_stats_t) {
+    let _op = op_lock();
+    *stats_buf = (*fs).cache_stats();
+}
+
+/// Commits the running transaction when it is due. Returns -1 if writing it
+/// to the image failed.
+#[no_mangle]
+pub unsafe extern "C" fn rs_end_op(fs: *mut FileSystem) -> i32 {
+    let _op = op_lock();
+    if (*fs).end_op().is_err() {
+        return -1;
+    }
+    0
+}
+
+/// Commits the running transaction when it is due, for a timer to call on
+/// an idle mount. Returns the milliseconds until the next one can be, or -1
+/// if writing it to the image failed.
+#[no_mangle]
+pub unsafe extern "C" fn rs_commit_timer(fs: *mut FileSystem) -> i64 {
+    let _op = op_lock();
+    match (*fs).commit_timer() {
+        Ok(left) => left.as_nanos().div_ceil(1_000_000) as i64,
+        Err(_) => -1,
+    }
+}
+
+/// Returns 1 if a checksum failed since the last call, which also marks the
+/// image as having errors, else 0.
+#[no_mangle]
+pub unsafe extern "C" fn rs_take_io_error(fs: *mut FileSystem) -> i32 {
+    let _op = op_lock();
+    (*fs).take_io_error() as i32
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rs_statfs(fs: *mut FileSystem, statfs_buf: *mut statfs_t) {
+    let _op = op_lock();
+    *statfs_buf = (*fs).statfs();
+}
+
+/// Returns the number of 512-byte sectors allocated to the file, or -1.
+#[no_mangle]
+pub unsafe extern "C" fn rs_getblocks(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
-) -> i32 {
-    if (*fs).get_attr(CStr::from_ptr(filename)).is_some() {
-        return 0;
+) -> i64 {
+    let _op = op_lock();
+    if let Some(blocks) = (*fs).get_blocks(CStr::from_ptr(filename)) {
+        return blocks as i64;
     }
     -1
 }
 
+/// Returns the inode number to use as the file handle, or -1.
+#[no_mangle]
+pub unsafe extern "C" fn rs_open(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+) -> i64 {
+    let _op = op_lock();
+    (*fs)
+        .lookup(CStr::from_ptr(filename))
+        .map_or(-1, |id| id as i64)
+}
+
+/// Returns the number of bytes read, 0 at the end of the file or -1 if it doesn't exist.
 #[no_mangle]
 pub unsafe extern "C" fn rs_read(
     fs: *mut FileSystem,
@@ -50,14 +120,28 @@ pub unsafe extern "C" fn rs_read(
     size: usize,
     offset: usize,
 ) -> i32 {
-    if let Ok(bytes) = (*fs).read_file(CStr::from_ptr(filename)) {
-        if bytes.len() > offset {
-            let size = size.min(bytes.len() - offset);
-            buf.copy_from(bytes[offset..].as_ptr() as *const i8, size);
-            size as i32
-        } else {
-            0
-        }
+    let _op = op_lock();
+    if let Some(id) = (*fs).lookup(CStr::from_ptr(filename)) {
+        let buf = slice::from_raw_parts_mut(buf as *mut u8, size);
+        (*fs).read_at(id, offset, buf) as i32
+    } else {
+        -1
+    }
+}
+
+/// Same as `rs_read` for a handle from `rs_open`; -1 if the handle is stale.
+#[no_mangle]
+pub unsafe extern "C" fn rs_read_fh(
+    fs: *mut FileSystem,
+    fh: u64,
+    buf: *mut i8,
+    size: usize,
+    offset: usize,
+) -> i32 {
+    let _op = op_lock();
+    if let Some(id) = (*fs).check_handle(fh) {
+        let buf = slice::from_raw_parts_mut(buf as *mut u8, size);
+        (*fs).read_at(id, offset, buf) as i32
     } else {
         -1
     }
@@ -70,32 +154,83 @@ pub unsafe extern "C" fn rs_readdir(
     buf: *mut ::std::os::raw::c_void,
     filler: fuse_fill_dir_t,
 ) -> i32 {
+    let _op = op_lock();
     let files = (*fs).get_files_in_dir(CStr::from_ptr(filename));
-    if files.len() > 0 {
+    if !files.is_empty() {
         for f in files {
             let name = CString::new(f).unwrap();
-            if filler(buf, name.as_ptr() as *const i8, 0 as *const i8, 0) != 0 {
+            if filler(buf, name.as_ptr(), std::ptr::null::<i8>(), 0) != 0 {
                 panic!("filler failed");
             }
         }
         return 0;
     }
-    return -1;
+    -1
 }
 
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_create(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
     mode: u32,
+    uid: u32,
+    gid: u32,
 ) -> i32 {
-    if (*fs)
-        .create_file(CStr::from_ptr(filename), &[], mode)
-        .is_ok()
-    {
-        return 0;
+    let _op = op_lock();
+    let (uid, gid) = match owner_ids(uid, gid) {
+        Ok(ids) => ids,
+        Err(e) => return -e.errno(),
+    };
+    match (*fs).create_file(CStr::from_ptr(filename), &[], mode, uid, gid) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Owner and group ids are kept in 16 bits, larger ones are refused with
+/// EOVERFLOW.
+fn owner_ids(uid: u32, gid: u32) -> Result<(u16, u16), Error> {
+    Ok((owner_id(uid)?, owner_id(gid)?))
+}
+
+fn owner_id(id: u32) -> Result<u16, Error> {
+    u16::try_from(id).map_err(|_| Error::Overflow)
+}
+
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_mknod(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    mode: u32,
+    rdev: u64,
+    uid: u32,
+    gid: u32,
+) -> i32 {
+    let _op = op_lock();
+    let (uid, gid) = match owner_ids(uid, gid) {
+        Ok(ids) => ids,
+        Err(e) => return -e.errno(),
+    };
+    let Some(rdev) = encode_dev(rdev) else {
+        return -Error::Invalid("device number too big").errno();
+    };
+    match (*fs).mknod(CStr::from_ptr(filename), mode, rdev, uid, gid) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
     }
-    return -1;
+}
+
+/// Packs a glibc `dev_t` into the 32 bits an inode keeps the way Linux
+/// does, with 12 bits of major and 20 of minor. `None` when it doesn't fit.
+fn encode_dev(dev: u64) -> Option<u32> {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    if major >= 1 << 12 || minor >= 1 << 20 {
+        return None;
+    }
+    Some(((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32)
 }
 
 #[no_mangle]
@@ -106,104 +241,545 @@ pub unsafe extern "C" fn rs_write(
     size: usize,
     offset: usize,
 ) -> i32 {
+    let _op = op_lock();
+    let Some(id) = (*fs).lookup(CStr::from_ptr(filename)) else {
+        return -Error::NotFound.errno();
+    };
     let content: &[u8] = slice::from_raw_parts(content as *const u8, size);
-    (*fs).write_file(CStr::from_ptr(filename), content, offset)
+    write_status((*fs).write_at(id, content, offset))
 }
 
+/// Same as `rs_write` for a handle from `rs_open`.
+#[no_mangle]
+pub unsafe extern "C" fn rs_write_fh(
+    fs: *mut FileSystem,
+    fh: u64,
+    content: *const ::std::os::raw::c_char,
+    size: usize,
+    offset: usize,
+) -> i32 {
+    let _op = op_lock();
+    let Some(id) = (*fs).check_handle(fh) else {
+        return -Error::StaleHandle.errno();
+    };
+    let content: &[u8] = slice::from_raw_parts(content as *const u8, size);
+    write_status((*fs).write_at(id, content, offset))
+}
+
+fn write_status(res: Result<usize, Error>) -> i32 {
+    match res {
+        Ok(len) => len as i32,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_mkdir(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
+    uid: u32,
+    gid: u32,
 ) -> i32 {
-    if (*fs).create_directory(CStr::from_ptr(filename)).is_ok() {
-        return 0;
+    let _op = op_lock();
+    let (uid, gid) = match owner_ids(uid, gid) {
+        Ok(ids) => ids,
+        Err(e) => return -e.errno(),
+    };
+    match (*fs).create_directory(CStr::from_ptr(filename), uid, gid) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
     }
-    return -1;
 }
 
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_unlink(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
 ) -> i32 {
-    if (*fs)
-        .unlink_file(CStr::from_ptr(filename).to_str().unwrap())
-        .is_ok()
-    {
-        return 0;
+    let _op = op_lock();
+    match (*fs).unlink_file(CStr::from_ptr(filename).to_str().unwrap()) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
     }
-    return -1;
 }
 
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_rmdir(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
 ) -> i32 {
-    if (*fs).unlink_dir(CStr::from_ptr(filename)).is_ok() {
-        return 0;
+    let _op = op_lock();
+    match (*fs).unlink_dir(CStr::from_ptr(filename)) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
     }
-    return -1;
 }
 
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_truncate(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
     size: usize,
 ) -> i32 {
-    if (*fs).truncate(CStr::from_ptr(filename), size).is_ok() {
-        return 0;
+    let _op = op_lock();
+    match (*fs).truncate(CStr::from_ptr(filename), size) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
     }
-    return -1;
 }
 
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_truncate_fh(fs: *mut FileSystem, fh: u64, size: usize) -> i32 {
+    let _op = op_lock();
+    let Some(id) = (*fs).check_handle(fh) else {
+        return -Error::StaleHandle.errno();
+    };
+    match (*fs).truncate_at(id, size) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rs_fsync(fs: *mut FileSystem, fh: u64) -> i32 {
+    let _op = op_lock();
+    if (*fs).check_handle(fh).is_none() || (*fs).sync().is_err() {
+        return -1;
+    }
+    0
+}
+
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_fallocate(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    mode: u32,
+    offset: usize,
+    len: usize,
+) -> i32 {
+    let _op = op_lock();
+    match (*fs).fallocate(CStr::from_ptr(filename), mode, offset, len) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_rename(
     fs: *mut FileSystem,
     from: *const ::std::os::raw::c_char,
     to: *const ::std::os::raw::c_char,
 ) -> i32 {
-    if (*fs)
-        .rename(CStr::from_ptr(from), CStr::from_ptr(to))
-        .is_ok()
-    {
+    let _op = op_lock();
+    match (*fs).rename(CStr::from_ptr(from), CStr::from_ptr(to)) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_link(
+    fs: *mut FileSystem,
+    from: *const ::std::os::raw::c_char,
+    to: *const ::std::os::raw::c_char,
+) -> i32 {
+    let _op = op_lock();
+    match (*fs).link(CStr::from_ptr(from), CStr::from_ptr(to)) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_symlink(
+    fs: *mut FileSystem,
+    target: *const ::std::os::raw::c_char,
+    linkpath: *const ::std::os::raw::c_char,
+    uid: u32,
+    gid: u32,
+) -> i32 {
+    let _op = op_lock();
+    let (uid, gid) = match owner_ids(uid, gid) {
+        Ok(ids) => ids,
+        Err(e) => return -e.errno(),
+    };
+    match (*fs).symlink(CStr::from_ptr(target), CStr::from_ptr(linkpath), uid, gid) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rs_readlink(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    buf: *mut ::std::os::raw::c_char,
+    size: usize,
+) -> i32 {
+    let _op = op_lock();
+    if size == 0 {
+        return -1;
+    }
+    if let Ok(target) = (*fs).read_link(CStr::from_ptr(filename)) {
+        let len = target.len().min(size - 1);
+        buf.copy_from(target.as_ptr() as *const ::std::os::raw::c_char, len);
+        *buf.add(len) = 0;
         return 0;
     }
-    return -1;
+    -1
 }
 
+/// Returns the negated errno on failure.
 #[no_mangle]
 pub unsafe extern "C" fn rs_chmod(
     fs: *mut FileSystem,
     filename: *const ::std::os::raw::c_char,
     mode: u32,
 ) -> i32 {
-    if (*fs).chmod(CStr::from_ptr(filename), mode).is_ok() {
-        return 0;
+    let _op = op_lock();
+    match (*fs).chmod(CStr::from_ptr(filename), mode) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_chown(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    uid: u32,
+    gid: u32,
+) -> i32 {
+    let _op = op_lock();
+    // u32::MAX is (uid_t)-1, meaning "leave unchanged"
+    let id = |id: u32| (id != u32::MAX).then(|| owner_id(id)).transpose();
+    let (uid, gid) = match (id(uid), id(gid)) {
+        (Ok(uid), Ok(gid)) => (uid, gid),
+        (Err(e), _) | (_, Err(e)) => return -e.errno(),
+    };
+    match (*fs).chown(CStr::from_ptr(filename), uid, gid) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the value length; the value is only copied when it fits in `size`.
+/// Returns the negated errno on failure, ENODATA when there is no such
+/// attribute.
+#[no_mangle]
+pub unsafe extern "C" fn rs_getxattr(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    name: *const ::std::os::raw::c_char,
+    buf: *mut ::std::os::raw::c_char,
+    size: usize,
+) -> i32 {
+    let _op = op_lock();
+    match (*fs).get_xattr(CStr::from_ptr(filename), CStr::from_ptr(name)) {
+        Ok(value) => {
+            if size >= value.len() {
+                buf.copy_from(value.as_ptr() as *const ::std::os::raw::c_char, value.len());
+            }
+            value.len() as i32
+        }
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure, ENOSPC when the attribute set no
+/// longer fits.
+#[no_mangle]
+pub unsafe extern "C" fn rs_setxattr(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    name: *const ::std::os::raw::c_char,
+    value: *const ::std::os::raw::c_char,
+    size: usize,
+) -> i32 {
+    let _op = op_lock();
+    let value: &[u8] = slice::from_raw_parts(value as *const u8, size);
+    match (*fs).set_xattr(CStr::from_ptr(filename), CStr::from_ptr(name), value) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the list length; the list is only copied when it fits in `size`.
+/// Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_listxattr(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    buf: *mut ::std::os::raw::c_char,
+    size: usize,
+) -> i32 {
+    let _op = op_lock();
+    match (*fs).list_xattr(CStr::from_ptr(filename)) {
+        Ok(list) => {
+            if size >= list.len() {
+                buf.copy_from(list.as_ptr() as *const ::std::os::raw::c_char, list.len());
+            }
+            list.len() as i32
+        }
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns the negated errno on failure, ENODATA when there is no such
+/// attribute.
+#[no_mangle]
+pub unsafe extern "C" fn rs_removexattr(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    name: *const ::std::os::raw::c_char,
+) -> i32 {
+    let _op = op_lock();
+    match (*fs).remove_xattr(CStr::from_ptr(filename), CStr::from_ptr(name)) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Identity of the calling process for the access checks, with its
+/// supplementary groups.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct cred_t {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: *const u32,
+    pub ngroups: usize,
+}
+
+impl cred_t {
+    unsafe fn to_cred(&self) -> Cred {
+        let groups = if self.ngroups == 0 {
+            vec![]
+        } else {
+            slice::from_raw_parts(self.groups, self.ngroups).to_vec()
+        };
+        Cred {
+            uid: self.uid,
+            gid: self.gid,
+            groups,
+        }
     }
-    return -1;
 }
 
+fn errno_status(res: Result<(), Error>) -> i32 {
+    match res {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Checks `mask` (R_OK, W_OK, X_OK) on the file, and search permission on
+/// the directories leading to it. Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_access(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    mask: u32,
+    cred: *const cred_t,
+) -> i32 {
+    let _op = op_lock();
+    errno_status((*fs).access(CStr::from_ptr(filename), &(*cred).to_cred(), mask as u16))
+}
+
+/// `rs_access` for the directory the file is in, as needed to create or
+/// remove it.
+#[no_mangle]
+pub unsafe extern "C" fn rs_access_parent(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    mask: u32,
+    cred: *const cred_t,
+) -> i32 {
+    let _op = op_lock();
+    errno_status((*fs).access_parent(CStr::from_ptr(filename), &(*cred).to_cred(), mask as u16))
+}
+
+/// Returns -EPERM unless the caller is root or owns the file and, when
+/// `uid` or `gid` isn't -1, may hand it to them.
+#[no_mangle]
+pub unsafe extern "C" fn rs_check_owner(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    uid: u32,
+    gid: u32,
+    cred: *const cred_t,
+) -> i32 {
+    let _op = op_lock();
+    let id = |id: u32| (id != u32::MAX).then_some(id);
+    errno_status((*fs).check_owner(
+        CStr::from_ptr(filename),
+        &(*cred).to_cred(),
+        id(uid),
+        id(gid),
+    ))
+}
+
+/// Takes -1 for a time to leave alone (UTIME_OMIT) and -2 for the current
+/// time (UTIME_NOW). Returns the negated errno on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rs_utimens(
+    fs: *mut FileSystem,
+    filename: *const ::std::os::raw::c_char,
+    atime: i64,
+    mtime: i64,
+) -> i32 {
+    let _op = op_lock();
+    let time = |t: i64| match t {
+        -2 => Some(current_time()),
+        t => u64::try_from(t).ok(),
+    };
+    match (*fs).set_times(CStr::from_ptr(filename), time(atime), time(mtime)) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Returns NULL, after printing why, when the image can't be opened or isn't
+/// one this driver can use.
 #[no_mangle]
 pub unsafe extern "C" fn rs_init<'a>(
     filename: *const ::std::os::raw::c_char,
 ) -> *mut FileSystem<'a> {
-    let name = CStr::from_ptr(filename);
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(name.to_str().expect("failed to open disk image"))
-        .expect("failed to open file");
-    let file = Box::into_raw(Box::new(file));
-    let map = Box::new(unsafe { MmapMut::map_mut(&(*file)).expect("failed mmap") });
-
-    let map = Box::into_raw(map);
+    let Ok(name) = CStr::from_ptr(filename).to_str() else {
+        eprintln!("can't open image: name isn't UTF-8");
+        return ptr::null_mut();
+    };
+    let file = match OpenOptions::new().read(true).write(true).open(name) {
+        Ok(file) => Box::into_raw(Box::new(file)),
+        Err(e) => {
+            eprintln!("can't open {name}: {e}");
+            return ptr::null_mut();
+        }
+    };
+    // private, changes only reach the file through the journal
+    let map = match unsafe { MmapOptions::new().map_copy(&(*file)) } {
+        Ok(map) => Box::into_raw(Box::new(map)),
+        Err(e) => {
+            eprintln!("can't map {name}: {e}");
+            drop(Box::from_raw(file));
+            return ptr::null_mut();
+        }
+    };
 
-    let f = unsafe { Box::new(FileSystem::new(&mut (*map)[..])) };
+    let mut f = match FileSystem::new(&mut (&mut *map)[..]) {
+        Ok(f) => Box::new(f),
+        Err(e) => {
+            eprintln!("can't use {name}: {e}");
+            drop(Box::from_raw(map));
+            drop(Box::from_raw(file));
+            return ptr::null_mut();
+        }
+    };
+    f.set_image(&*file, &*map);
+    if let Err(e) = f.mount() {
+        eprintln!("can't mount {name}: {e}");
+        drop(f);
+        drop(Box::from_raw(map));
+        drop(Box::from_raw(file));
+        return ptr::null_mut();
+    }
     Box::into_raw(f)
 }
 
+/// Checks the image without mounting it, after replaying its journal, and
+/// prints what is wrong. An image that is mounted is only read. Returns the
+/// number of problems found, or -1, after printing why, when the image
+/// can't be opened or written.
+#[no_mangle]
+pub unsafe extern "C" fn rs_fsck(filename: *const ::std::os::raw::c_char) -> i32 {
+    let Ok(name) = CStr::from_ptr(filename).to_str() else {
+        eprintln!("can't open image: name isn't UTF-8");
+        return -1;
+    };
+    let file = match OpenOptions::new().read(true).write(true).open(name) {
+        Ok(file) => Box::into_raw(Box::new(file)),
+        Err(e) => {
+            eprintln!("can't open {name}: {e}");
+            return -1;
+        }
+    };
+    // the journal and the state of a mounted image belong to the mount
+    let in_use = matches!((*file).try_lock(), Err(TryLockError::WouldBlock));
+    if in_use {
+        eprintln!("{name} is in use, checking it without writing to it");
+    }
+    let map = match unsafe { MmapOptions::new().map_copy(&(*file)) } {
+        Ok(map) => Box::into_raw(Box::new(map)),
+        Err(e) => {
+            eprintln!("can't map {name}: {e}");
+            drop(Box::from_raw(file));
+            return -1;
+        }
+    };
+
+    let res = match FileSystem::new(&mut (&mut *map)[..]) {
+        Ok(mut f) => {
+            // without the file nothing gets committed to it
+            if !in_use {
+                f.set_image(&*file, &*map);
+            }
+            f.fsck().map_err(|e| format!("can't write {name}: {e}"))
+        }
+        Err(e) => Err(format!("can't use {name}: {e}")),
+    };
+    drop(Box::from_raw(map));
+    drop(Box::from_raw(file));
+    match res {
+        Ok(problems) => {
+            for p in &problems {
+                println!("{p}");
+            }
+            problems.len() as i32
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            -1
+        }
+    }
+}
+
+/// Marks the image clean and commits it. Returns -1 if writing it fails.
+#[no_mangle]
+pub unsafe extern "C" fn rs_unmount(fs: *mut FileSystem) -> i32 {
+    let _op = op_lock();
+    if (*fs).unmount().is_err() {
+        return -1;
+    }
+    0
+}
+
+/// Returns -1 when the label is longer than 16 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rs_set_label(
+    fs: *mut FileSystem,
+    label: *const ::std::os::raw::c_char,
+) -> i32 {
+    let _op = op_lock();
+    let Ok(label) = CStr::from_ptr(label).to_str() else {
+        return -1;
+    };
+    if (*fs).set_label(label).is_err() {
+        return -1;
+    }
+    0
+}
+
+/// Returns NULL when the image can't be laid out with the given geometry.
 #[no_mangle]
 pub unsafe extern "C" fn rs_init_and_format<'a>(
     filename: *const ::std::os::raw::c_char,
@@ -212,31 +788,60 @@ pub unsafe extern "C" fn rs_init_and_format<'a>(
     inode_num: u32,
 ) -> *mut FileSystem<'a> {
     let name = CStr::from_ptr(filename);
+    if let Err(e) = FileSystem::check_geometry(block_size, block_num, inode_num) {
+        eprintln!("invalid geometry: {e}");
+        return ptr::null_mut();
+    }
 
     let file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(name.to_str().expect("failed to open disk image"))
         .expect("failed to open file");
     file.set_len(block_size * block_num).expect("OOM");
+    // the root directory belongs to whoever formats the image
+    let meta = file.metadata().expect("failed to stat disk image");
+    let (uid, gid) = match owner_ids(meta.uid(), meta.gid()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("can't format {}: owner {e}", name.to_string_lossy());
+            return ptr::null_mut();
+        }
+    };
     let file = Box::into_raw(Box::new(file));
-    let mut map = Box::new(unsafe { MmapMut::map_mut(&(*file)).expect("failed mmap") });
+    let mut map = Box::new(unsafe { MmapOptions::new().map_copy(&(*file)).expect("failed mmap") });
 
-    let sb_data: [u8; 20] = map[0..20].try_into().unwrap();
+    let sb_data: [u8; SB_SIZE] = map[0..SB_SIZE].try_into().unwrap();
     let mut sb: superblock_t = zerocopy::transmute!(sb_data);
-    sb.header = [0x58, 0x44, 0x20, 0x20, 0x20, 0x20, 0x58, 0x44];
+    sb.header = SB_MAGIC;
     sb.block_size = block_size as u32;
     sb.blocks_num = block_num as u32;
     sb.inodes_num = inode_num;
-    let d: [u8; 20] = zerocopy::transmute!(sb);
-    map[..20].copy_from_slice(&d);
+    let d: [u8; SB_SIZE] = zerocopy::transmute!(sb);
+    map[..SB_SIZE].copy_from_slice(&d);
 
     let map = Box::into_raw(map);
 
-    let mut f = unsafe { Box::new(FileSystem::new(&mut (*map)[..])) };
+    let mut f = match FileSystem::new(&mut (&mut *map)[..]) {
+        Ok(f) => Box::new(f),
+        Err(e) => {
+            eprintln!("can't use formatted image: {e}");
+            drop(Box::from_raw(map));
+            drop(Box::from_raw(file));
+            return ptr::null_mut();
+        }
+    };
+    f.set_image(&*file, &*map);
     // println!("{:?}", f);
-    f.format();
+    if let Err(e) = f.format(uid, gid) {
+        eprintln!("can't write formatted image: {e}");
+        drop(f);
+        drop(Box::from_raw(map));
+        drop(Box::from_raw(file));
+        return ptr::null_mut();
+    }
     // f.dummy_data();
     Box::into_raw(f)
 }
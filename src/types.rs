@@ -1,10 +1,37 @@
+mod acl;
+mod bmap;
+mod cache;
+mod csum;
+mod error;
+mod extent;
+mod fallocate;
+mod fsck;
+mod htree;
+mod journal;
+#[cfg(test)]
+mod testing;
+mod xattr;
+
 use std::{
+    cell::Cell,
+    collections::{hash_map::RandomState, BTreeSet, HashSet},
     ffi::CStr,
+    fs::{File, TryLockError},
+    hash::{BuildHasher, Hasher},
+    mem::offset_of,
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use memmap2::MmapMut;
 use zerocopy::FromZeros;
 
+pub use acl::Cred;
+use cache::Cache;
+use csum::crc32c;
+pub use error::Error;
+use extent::init_extents;
+use journal::Journal;
+
 #[derive(Debug)]
 pub struct FileSystem<'a> {
     sb: superblock_t,
@@ -13,62 +40,156 @@ pub struct FileSystem<'a> {
     inodes: &'a mut [u8],
     blocks_bitmap: Bitmap<'a>,
     data_blocks: &'a mut [u8],
+    image: Option<&'a File>,
+    map: Option<&'a MmapMut>,
+    cache: Cache,
+    journal: Journal,
+    /// Set when a checksum fails or an entry is mangled, until
+    /// `take_io_error`. Operations check it before writing anything.
+    io_error: Cell<bool>,
 }
 
 impl<'a> FileSystem<'a> {
-    pub fn new(data: &'a mut [u8]) -> Self {
-        let sb_data: [u8; 20] = data[0..20].try_into().unwrap();
-        let sb: superblock_t = zerocopy::transmute!(sb_data);
-
-        let inode_bitmap_id = 1;
-        let inodes_id = if sb.inodes_num % (8 * sb.block_size) == 0 {
-            sb.inodes_num / (8 * sb.block_size) + inode_bitmap_id
-        } else {
-            sb.inodes_num / (8 * sb.block_size) + 2
-        };
-
-        let blocks_bitmap_id = if (sb.inodes_num * 128) % (sb.block_size) == 0 {
-            (sb.inodes_num * 128) / (sb.block_size) + inodes_id
-        } else {
-            (sb.inodes_num * 128) / (sb.block_size) + inodes_id + 1
-        };
-
-        let data_blocks = sb.blocks_num - blocks_bitmap_id;
-        let first_block_id = if data_blocks % (8 * sb.block_size) == 0 {
-            data_blocks / (8 * sb.block_size) + blocks_bitmap_id
-        } else {
-            data_blocks / (8 * sb.block_size) + blocks_bitmap_id + 1
-        };
-
-        let (data, blocks_data) = data.split_at_mut((first_block_id * sb.block_size) as usize);
-        let (data, blocks_bitmap_data) =
-            data.split_at_mut((blocks_bitmap_id * sb.block_size) as usize);
-        let (data, inodes_data) = data.split_at_mut((inodes_id * sb.block_size) as usize);
-        let (data, inodes_bitmap_data) = data.split_at_mut(sb.block_size as usize);
-        let inode_bitmap = Bitmap::new(inodes_bitmap_data, sb.inodes_num as usize);
+    /// Lays out the image in `data` after checking that its superblock
+    /// describes something this driver can use and that fits in `data`,
+    /// replaying the journal if the image wasn't cleanly unmounted.
+    pub fn new(data: &'a mut [u8]) -> Result<Self, Error> {
+        Self::check_superblock(data)?;
+        let journal = Journal::open(data)?;
+        // the replay may have put back an older superblock
+        Self::check_superblock(data)?;
+        let sb_data: [u8; SB_SIZE] = data[0..SB_SIZE].try_into().unwrap();
+        let mut sb: superblock_t = zerocopy::transmute!(sb_data);
+        let bs = sb.block_size as usize;
+
+        let (inodes_id, blocks_bitmap_id, first_block_id) = Self::layout(&sb);
+
+        let (data, blocks_data) = data.split_at_mut(first_block_id as usize * bs);
+        let (data, blocks_bitmap_data) = data.split_at_mut(blocks_bitmap_id as usize * bs);
+        let (data, inodes_data) = data.split_at_mut(inodes_id as usize * bs);
+        let (data, inodes_bitmap_data) = data.split_at_mut(bs);
+        let inode_bitmap = Bitmap::new(inodes_bitmap_data, sb.inodes_num as usize, bs);
         let blocks_bitmap = Bitmap::new(
             blocks_bitmap_data,
-            (sb.blocks_num - first_block_id) as usize,
+            (sb.blocks_num as u64 - first_block_id) as usize,
+            bs,
         );
         // println!(
         //     "{inodes_id} {blocks_bitmap_id} {first_block_id} {}",
         //     sb.blocks_num - first_block_id
         // );
+        // the bitmaps are what counts, also on images from before the counters
+        sb.free_blocks_num = blocks_bitmap.free as u32;
+        sb.free_inodes_num = inode_bitmap.free as u32;
 
-        Self {
+        Ok(Self {
             sb,
             data,
             inode_bitmap,
             inodes: inodes_data,
             blocks_bitmap,
             data_blocks: blocks_data,
+            image: None,
+            map: None,
+            cache: Cache::default(),
+            journal,
+            io_error: Cell::new(false),
+        })
+    }
+
+    /// First blocks of the inode table, the blocks bitmap and the data area.
+    /// Worked out in 64 bits, the products overflow `u32` on large images.
+    fn layout(sb: &superblock_t) -> (u64, u64, u64) {
+        let bs = sb.block_size as u64;
+        let inode_bitmap_id = 1;
+        let inodes_id = (sb.inodes_num as u64).div_ceil(8 * bs) + inode_bitmap_id;
+        let blocks_bitmap_id = (sb.inodes_num as u64 * 128).div_ceil(bs) + inodes_id;
+        let data_blocks = (sb.blocks_num as u64).saturating_sub(blocks_bitmap_id);
+        let first_block_id = data_blocks.div_ceil(8 * bs) + blocks_bitmap_id;
+        (inodes_id, blocks_bitmap_id, first_block_id)
+    }
+
+    /// Rejects image geometries that can't be laid out or addressed.
+    pub fn check_geometry(block_size: u64, block_num: u64, inode_num: u32) -> Result<(), Error> {
+        if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size) {
+            return Err(Error::Invalid(
+                "block size must be a power of two between 512 and 65536",
+            ));
+        }
+        if block_num > u32::MAX as u64 {
+            return Err(Error::Invalid(
+                "more blocks than 32-bit block numbers can address",
+            ));
+        }
+        if block_size
+            .checked_mul(block_num)
+            .is_none_or(|size| usize::try_from(size).is_err())
+        {
+            return Err(Error::Invalid("image too big to map"));
         }
+        // inode 0 is never used and inode 1 is the root directory
+        if inode_num < 2 {
+            return Err(Error::Invalid("need at least 2 inodes"));
+        }
+        let sb = superblock_t {
+            inodes_num: inode_num,
+            blocks_num: block_num as u32,
+            block_size: block_size as u32,
+            ..superblock_t::new_zeroed()
+        };
+        let (_, _, first_block_id) = Self::layout(&sb);
+        // data block 0 is reserved and block 1 holds the root directory
+        if first_block_id + 2 > block_num {
+            return Err(Error::Invalid(
+                "not enough blocks for the metadata and root directory",
+            ));
+        }
+        Ok(())
     }
 
-    pub fn format(&mut self) {
-        self.sb.header = [0x58, 0x44, 0x20, 0x20, 0x20, 0x20, 0x58, 0x44];
-        self.save();
-        self.create_inode(1, 1, 0, 0x4000 | 0o755);
+    fn check_superblock(data: &[u8]) -> Result<(), Error> {
+        let Some(sb_data) = data.get(..SB_SIZE) else {
+            return Err(Error::Invalid("image too small"));
+        };
+        let sb_data: [u8; SB_SIZE] = sb_data.try_into().unwrap();
+        let sb: superblock_t = zerocopy::transmute!(sb_data);
+        if sb.header != SB_MAGIC {
+            return Err(Error::Invalid("not an oxidizedFS image"));
+        }
+        if sb.feature_ro_compat & FEATURE_METADATA_CSUM != 0
+            && sb.checksum != crc32c(0, &sb_data[..SB_SIZE - 4])
+        {
+            return Err(Error::Io("superblock checksum mismatch"));
+        }
+        Self::check_geometry(sb.block_size as u64, sb.blocks_num as u64, sb.inodes_num)?;
+        if (sb.block_size as u64 * sb.blocks_num as u64) > data.len() as u64 {
+            return Err(Error::Invalid("image smaller than its superblock says"));
+        }
+        if sb.feature_incompat & !FEATURE_INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::Unsupported(
+                "image uses unknown incompatible features",
+            ));
+        }
+        // there's no read-only mount to fall back to
+        if sb.feature_ro_compat & !FEATURE_RO_COMPAT_SUPPORTED != 0 {
+            return Err(Error::Unsupported(
+                "image uses unknown read-only compatible features",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes an empty filesystem to the image, with a journal if it has
+    /// room for one.
+    pub fn format(&mut self, uid: u16, gid: u16) -> Result<(), Error> {
+        self.sb.header = SB_MAGIC;
+        self.sb.version = SB_VERSION;
+        self.sb.feature_incompat = FEATURE_LARGE_FILE | FEATURE_EXTENTS;
+        self.sb.feature_ro_compat = FEATURE_METADATA_CSUM;
+        self.sb.uuid = new_uuid();
+        self.sb.creat_time = current_time();
+        self.sb.state = STATE_CLEAN;
+        self.create_inode(1, 1, 0, 0x4000 | 0o755, uid, gid);
 
         let inode_num = 1;
         let mut data = [0u8; 22];
@@ -79,429 +200,538 @@ impl<'a> FileSystem<'a> {
         data[12..16].copy_from_slice(&(inode_num as u32).to_le_bytes());
         data[16..20].copy_from_slice(&2u32.to_le_bytes());
         data[20..22].copy_from_slice("..".as_bytes());
-        self.get_data_block_mut(1 as u32)[0..data.len()].copy_from_slice(&data);
+        self.get_data_block_mut(1)[0..data.len()].copy_from_slice(&data);
+        self.seal_dir_block(1);
         self.inode_bitmap.take(1);
         self.blocks_bitmap.take(1);
+        self.create_journal();
+        self.save();
+        self.commit()
     }
 
-    pub fn rename(&mut self, from: &CStr, to: &CStr) -> Result<(), &str> {
+    pub fn rename(&mut self, from: &CStr, to: &CStr) -> Result<(), Error> {
         let from = from.to_str().expect("path should be UTF-8");
         let to = to.to_str().expect("path should be UTF-8");
         if let Some(offset) = from.rfind('/') {
-            if let Some(dir_from) = if offset == 0 {
-                Some(self.get_inode_by_id(1))
+            if let Some((dir_from, from_id)) = if offset == 0 {
+                self.load_inode(1).ok().map(|root| (root, 1))
             } else {
-                self.find_file(&from[..offset])
+                self.find_file_mut(&from[..offset])
             } {
                 if dir_from.is_directory() {
                     if let Some(id) = self.search_directory_get_id(&dir_from, &from[offset + 1..]) {
-                        if let Some(to_remove) = self.find_file(to) {
+                        self.load_inode(id)?;
+                        if let Some((to_remove, to_remove_id)) = self.find_file_mut(to) {
+                            if to_remove_id == id {
+                                // both names are links to the same inode
+                                return Ok(());
+                            }
                             if to_remove.is_directory() {
-                                return Err("file already exists");
+                                return Err(Error::Exists);
                             }
                             self.unlink_file(to)?;
                         }
+                        // a name in a block that failed its checksum looks free
+                        self.check_io()?;
                         if let Some(to_offset) = to.rfind('/') {
                             if let Some((dir_to, node_id)) = if to_offset == 0 {
-                                Some((self.get_inode_by_id(1), 1))
+                                self.load_inode(1).ok().map(|root| (root, 1))
                             } else {
                                 self.find_file_mut(&to[..to_offset])
                             } {
-                                self.clear_dentry(&dir_from, &from[offset + 1..]);
-
                                 // create dentry
-                                let name = &to[to_offset + 1..].as_bytes();
-                                self.create_dentry(&dir_to, node_id, id, name);
+                                let name = &to.as_bytes()[to_offset + 1..];
+                                self.create_dentry(&dir_to, node_id, id, name)?;
+                                // adding the entry may have indexed the directory
+                                let dir_from = self.load_inode(from_id)?;
+                                self.clear_dentry(&dir_from, from_id, &from[offset + 1..])?;
+
+                                let mut node = self.get_inode_by_id(id);
+                                node.touch_changed();
+                                self.save_inode(id, node);
+                                if node.is_directory() && from_id != node_id {
+                                    self.set_dotdot(id, node_id)?;
+                                    let mut parent = self.get_inode_by_id(from_id);
+                                    parent.hard_links -= 1;
+                                    self.save_inode(from_id, parent);
+                                    let mut parent = self.get_inode_by_id(node_id);
+                                    parent.hard_links += 1;
+                                    self.save_inode(node_id, parent);
+                                }
                                 return Ok(());
                             }
-                            return Err("target dir nonexisting");
+                            return Err(Error::NotFound);
                         }
                     }
                 }
             }
-            return Err("file not found");
+            Err(Error::NotFound)
         } else {
-            return Err("bad filename format");
+            Err(Error::Invalid("bad filename format"))
+        }
+    }
+
+    /// Image file behind `map`, a private mapping of it that `commit`
+    /// writes back from.
+    pub fn set_image(&mut self, file: &'a File, map: &'a MmapMut) {
+        self.image = Some(file);
+        self.map = Some(map);
+    }
+
+    /// Counts the mount and marks the image dirty until `unmount`. The
+    /// image file stays locked meanwhile, for fsck and other mounts to keep
+    /// off it.
+    pub fn mount(&mut self) -> Result<(), Error> {
+        if let Some(file) = self.image {
+            if let Err(TryLockError::WouldBlock) = file.try_lock() {
+                return Err(Error::Io("image is in use elsewhere"));
+            }
+        }
+        // images from before the state flag don't know
+        if self.sb.version != 0 && self.sb.state & STATE_CLEAN == 0 {
+            eprintln!("image was not cleanly unmounted");
         }
+        if self.sb.state & STATE_ERRORS != 0 {
+            eprintln!("image has errors, check it with fsck");
+        }
+        self.sb.version = SB_VERSION;
+        self.sb.mount_count = self.sb.mount_count.wrapping_add(1);
+        self.sb.mount_time = current_time();
+        self.sb.state &= !STATE_CLEAN;
+        self.save();
+        self.commit()
     }
 
+    pub fn unmount(&mut self) -> Result<(), Error> {
+        self.sb.state |= STATE_CLEAN;
+        self.sync()?;
+        if let Some(file) = self.image {
+            let _ = file.unlock();
+        }
+        Ok(())
+    }
+
+    /// Sets the volume label, at most 16 bytes.
+    pub fn set_label(&mut self, label: &str) -> Result<(), Error> {
+        if label.len() > self.sb.label.len() {
+            return Err(Error::Invalid("label too long"));
+        }
+        self.sb.label = [0; 16];
+        for (c, b) in self.sb.label.iter_mut().zip(label.bytes()) {
+            *c = b as ::std::os::raw::c_char;
+        }
+        self.save();
+        Ok(())
+    }
+
+    /// Commits everything written so far, which also flushes it to disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.save();
+        self.commit()
+    }
+
+    /// Writes the superblock back along with the current free counts.
     pub fn save(&mut self) {
-        let d: [u8; 20] = zerocopy::transmute!(self.sb);
-        self.data[..20].copy_from_slice(&d);
+        self.sb.free_blocks_num = self.blocks_bitmap.free as u32;
+        self.sb.free_inodes_num = self.inode_bitmap.free as u32;
+        self.sb.write_time = current_time();
+        let mut d: [u8; SB_SIZE] = zerocopy::transmute!(self.sb);
+        if self.csum_enabled() {
+            self.sb.checksum = crc32c(0, &d[..SB_SIZE - 4]);
+            d[SB_SIZE - 4..].copy_from_slice(&self.sb.checksum.to_le_bytes());
+        }
+        self.data[..SB_SIZE].copy_from_slice(&d);
+        self.journal.touch(0);
+    }
+
+    /// The counts come from the bitmaps, the superblock only catches up on
+    /// the next `save`.
+    pub fn statfs(&self) -> statfs_t {
+        statfs_t {
+            block_size: self.sb.block_size,
+            blocks_num: self.sb.blocks_num,
+            free_blocks_num: self.blocks_bitmap.free as u32,
+            inodes_num: self.sb.inodes_num,
+            free_inodes_num: self.inode_bitmap.free as u32,
+            name_max: NAME_MAX as u32,
+        }
     }
 
     pub fn get_attr(&self, path: &CStr) -> Option<inode_t> {
         self.find_file(path.to_str().unwrap())
     }
 
-    pub fn create_file(&mut self, path: &CStr, content: &[u8], mode: u32) -> Result<(), &str> {
-        return self.create_file_inter(path, content, mode as u16);
+    pub fn create_file(
+        &mut self,
+        path: &CStr,
+        content: &[u8],
+        mode: u32,
+        uid: u16,
+        gid: u16,
+    ) -> Result<(), Error> {
+        self.create_file_inter(path, content, mode as u16, uid, gid)?;
+        Ok(())
     }
 
-    pub fn unlink_file(&mut self, path: &str) -> Result<(), &'static str> {
-        // TODO check nlink
+    pub fn unlink_file(&mut self, path: &str) -> Result<(), Error> {
         // let path = path.to_str().unwrap();
         if let Some(offset) = path.rfind('/') {
-            if let Some(node) = if offset == 0 {
-                Some(self.get_inode_by_id(1))
+            if let Some((node, node_id)) = if offset == 0 {
+                self.load_inode(1).ok().map(|root| (root, 1))
             } else {
-                self.find_file(&path[..offset])
+                self.find_file_mut(&path[..offset])
             } {
                 if node.is_directory() {
                     if let Some(id) = self.search_directory_get_id(&node, &path[offset + 1..]) {
-                        let file = self.get_inode_by_id(id);
+                        let mut file = self.load_inode(id)?;
+                        self.clear_dentry(&node, node_id, &path[offset + 1..])?;
                         println!("{file:?}");
-                        self.truncate_inter(file, id, 0).unwrap();
-                        self.inode_bitmap.free(id as usize);
-                        self.clear_dentry(&node, &path[offset + 1..]);
+                        file.hard_links -= 1;
+                        if file.hard_links == 0 {
+                            if file.is_fast_symlink() {
+                                // the block pointers hold the target, not blocks
+                                file.set_inline_data(&[]);
+                            }
+                            self.free_xattrs(&mut file);
+                            self.truncate_inter(file, id, 0).unwrap();
+                            self.inode_bitmap.free(id as usize);
+                        } else {
+                            file.touch_changed();
+                            self.save_inode(id, file);
+                        }
 
                         return Ok(());
                     }
                 }
             }
-            return Err("file not found");
+            Err(Error::NotFound)
         } else {
-            return Err("bad filename format");
+            Err(Error::Invalid("bad filename format"))
         }
     }
 
-    pub fn unlink_dir(&mut self, path: &CStr) -> Result<(), &str> {
+    pub fn unlink_dir(&mut self, path: &CStr) -> Result<(), Error> {
         let path = path.to_str().unwrap();
         if let Some(offset) = path.rfind('/') {
-            if let Some(node) = if offset == 0 {
-                Some(self.get_inode_by_id(1))
+            if let Some((node, parent_id)) = if offset == 0 {
+                self.load_inode(1).ok().map(|root| (root, 1))
             } else {
-                self.find_file(&path[..offset])
+                self.find_file_mut(&path[..offset])
             } {
                 if node.is_directory() {
                     if let Some(id) = self.search_directory_get_id(&node, &path[offset + 1..]) {
-                        let file = self.get_inode_by_id(id);
+                        let mut file = self.load_inode(id)?;
                         if file.is_directory() {
                             let all_data = self.get_dir_data(&file);
                             let mut data = &all_data[..];
-                            while let Some(d) = Dentry::from(&data[..]) {
+                            while let Some(d) = self.dentry(data) {
                                 println!("{:?}", d);
                                 if !(d.name == "." || d.name == "..") {
-                                    return Err("directory not empty");
+                                    return Err(Error::NotEmpty);
                                 }
                                 data = &data[d.size..];
                             }
+                            // a block that failed its checksum looked empty
+                            self.check_io()?;
+                            self.clear_dentry(&node, parent_id, &path[offset + 1..])?;
+                            self.free_xattrs(&mut file);
+                            self.free_index(&mut file);
                             self.truncate_inter(file, id, 0).unwrap();
                             self.inode_bitmap.free(id as usize);
-                            self.clear_dentry(&node, &path[offset + 1..]);
+                            self.forget_dir(id);
+
+                            // drop the link held by the removed ".."
+                            let mut parent = self.get_inode_by_id(parent_id);
+                            parent.hard_links -= 1;
+                            self.save_inode(parent_id, parent);
 
                             return Ok(());
                         }
-                        return Err("not a directory");
+                        return Err(Error::NotDirectory);
                     }
                 }
             }
-            return Err("file not found");
+            return Err(Error::NotFound);
         }
-        Err("bad filename format")
+        Err(Error::Invalid("bad filename format"))
     }
 
-    fn find_file(&self, path: &str) -> Option<inode_t> {
-        let root = self.get_inode_by_id(1);
-        if path == "/" {
-            return Some(root);
-        }
-        if &path[0..1] == "/" {
-            return self.find_file_inter(&root, &path[1..]);
+    pub fn link(&mut self, from: &CStr, to: &CStr) -> Result<(), Error> {
+        let from = from.to_str().expect("path should be UTF-8");
+        let to = to.to_str().expect("path should be UTF-8");
+        let Some((mut node, id)) = self.find_file_mut(from) else {
+            return Err(Error::NotFound);
+        };
+        if node.is_directory() {
+            return Err(Error::NotPermitted);
         }
-        None
-    }
-
-    fn find_file_inter(&self, node: &inode_t, path: &str) -> Option<inode_t> {
-        if let Some(offset) = path.find('/') {
-            let filename = &path[0..offset];
-            // println!("{:?}", filename.as_bytes());
-            if let Some(sub_node) = self.search_directory(node, filename) {
-                if sub_node.is_directory() {
-                    // println!(
-                    //     "subnode foid off {}searching {}",
-                    //     offset,
-                    //     &path[offset + 1..]
-                    // );
-                    return self.find_file_inter(&sub_node, &path[offset + 1..]);
-                }
-            } else {
-                return None;
-            }
+        let Some(offset) = to.rfind('/') else {
+            return Err(Error::Invalid("bad filename format"));
+        };
+        let Some((dir, dir_id)) = (if offset == 0 {
+            self.load_inode(1).ok().map(|root| (root, 1))
         } else {
-            return self.search_directory(node, path);
+            self.find_file_mut(&to[..offset])
+        }) else {
+            return Err(Error::NotFound);
         };
+        if !dir.is_directory() {
+            return Err(Error::NotDirectory);
+        }
+        if self.search_directory(&dir, &to[offset + 1..]).is_some() {
+            return Err(Error::Exists);
+        }
+        self.check_io()?;
+        self.create_dentry(&dir, dir_id, id, &to.as_bytes()[offset + 1..])?;
+        node.hard_links += 1;
+        node.touch_changed();
+        self.save_inode(id, node);
+        Ok(())
+    }
 
-        None
+    fn find_file(&self, path: &str) -> Option<inode_t> {
+        self.resolve(path, None).ok().map(|(node, _)| node)
     }
 
     fn find_file_mut(&self, path: &str) -> Option<(inode_t, inode_p)> {
-        let root = self.get_inode_by_id(1);
+        self.resolve(path, None).ok()
+    }
+
+    /// Path walk that, given `cred`, requires search permission on every
+    /// directory it looks into.
+    fn resolve(&self, path: &str, cred: Option<&Cred>) -> Result<(inode_t, inode_p), Error> {
+        let root = self.load_inode(1)?;
         if path == "/" {
-            return Some((root, 1));
+            return Ok((root, 1));
         }
-        if &path[0..1] == "/" {
-            return self.find_file_mut_inter(&root, &path[1..]);
+        if let Some(path) = path.strip_prefix('/') {
+            return self.resolve_inter(&root, 1, path, 0, cred);
         }
-        None
+        Err(Error::Invalid("bad filename format"))
     }
 
-    fn find_file_mut_inter(&self, node: &inode_t, path: &str) -> Option<(inode_t, inode_p)> {
-        if let Some(offset) = path.find('/') {
-            let filename = &path[0..offset];
-            // println!("{:?}", filename.as_bytes());
-            if let Some(sub_node) = self.search_directory(node, filename) {
-                if sub_node.is_directory() {
-                    // println!(
-                    //     "subnode foid off {}searching {}",
-                    //     offset,
-                    //     &path[offset + 1..]
-                    // );
-                    return self.find_file_mut_inter(&sub_node, &path[offset + 1..]);
-                }
-            } else {
-                return None;
-            }
-        } else {
-            if let Some(id) = self.search_directory_get_id(node, path) {
-                return Some((self.get_inode_by_id(id), id));
+    fn resolve_inter(
+        &self,
+        node: &inode_t,
+        node_id: inode_p,
+        path: &str,
+        links: u32,
+        cred: Option<&Cred>,
+    ) -> Result<(inode_t, inode_p), Error> {
+        if let Some(cred) = cred {
+            if !self.permission(node, cred, 1) {
+                return Err(Error::Access);
             }
+        }
+        let (filename, rest) = match path.find('/') {
+            Some(offset) => (&path[0..offset], Some(&path[offset + 1..])),
+            None => (path, None),
         };
-
-        None
+        let id = self
+            .lookup_child(node_id, node, filename)
+            .ok_or(Error::NotFound)?;
+        let sub_node = self.load_inode(id)?;
+        let Some(rest) = rest else {
+            return Ok((sub_node, id));
+        };
+        if sub_node.is_directory() {
+            return self.resolve_inter(&sub_node, id, rest, links, cred);
+        }
+        if !sub_node.is_symlink() {
+            return Err(Error::NotDirectory);
+        }
+        if links < MAX_SYMLINKS {
+            if let Some((start, start_id, path)) =
+                self.follow_symlink(node, node_id, &sub_node, rest)
+            {
+                return self.resolve_inter(&start, start_id, &path, links + 1, cred);
+            }
+        }
+        Err(Error::NotFound)
     }
 
-    pub fn create_directory(&mut self, path: &CStr) -> Result<(), &str> {
-        let data = vec![0; self.sb.block_size as usize];
-        self.create_file_inter(path, &data, 0x4000 | 0o755)
+    /// Returns the node to continue the lookup from and the path left to
+    /// resolve after replacing `link` (found in `dir`) with its target.
+    fn follow_symlink(
+        &self,
+        dir: &inode_t,
+        dir_id: inode_p,
+        link: &inode_t,
+        rest: &str,
+    ) -> Option<(inode_t, inode_p, String)> {
+        let target = self.read_link_target(link).ok()?;
+        let target = std::str::from_utf8(&target).ok()?;
+        if let Some(target) = target.strip_prefix('/') {
+            let target = target.trim_end_matches('/');
+            if target.is_empty() {
+                return Some((self.get_inode_by_id(1), 1, rest.to_string()));
+            }
+            return Some((self.get_inode_by_id(1), 1, format!("{target}/{rest}")));
+        }
+        let target = target.trim_end_matches('/');
+        if target.is_empty() {
+            return None;
+        }
+        Some((*dir, dir_id, format!("{target}/{rest}")))
     }
 
-    fn write_to_indirect_block(
-        &mut self,
-        ind_block_num: u32,
-        content: &[u8],
-        offset: usize,
-    ) -> Result<usize, &'static str> {
-        let bs = self.sb.block_size as usize;
-        let total = content.len();
-        let mut block_num = offset / bs;
-        let mut content = content;
+    pub fn symlink(&mut self, target: &CStr, path: &CStr, uid: u16, gid: u16) -> Result<(), Error> {
+        let target = target.to_bytes();
+        if target.is_empty() {
+            return Err(Error::NotFound);
+        }
+        if target.len() >= PATH_MAX {
+            return Err(Error::NameTooLong);
+        }
+        let id = self.create_file_inter(path, &[], 0xA000 | 0o777, uid, gid)?;
+        let mut node = self.get_inode_by_id(id);
+        if target.len() <= SYMLINK_INLINE_MAX {
+            node.set_inline_data(target);
+            node.size = target.len() as u64;
+            self.save_inode(id, node);
+            return Ok(());
+        }
+        let res = self
+            .truncate_inter(node, id, target.len() as isize)
+            .and_then(|_| self.write_file_data(id, target, 0));
+        if res.is_err() {
+            // don't leave a link to a cut off target behind
+            let _ = self.unlink_file(path.to_str().expect("path should be UTF-8"));
+        }
+        res
+    }
 
-        let start = offset % bs;
-        let batch = if content.len() < bs - start {
-            content.len()
-        } else {
-            bs - start
-        };
-        let indirect_data = self.get_data_block(ind_block_num);
-        // println!("{ind_block_num}");
-        // println!("{indirect_data:?}");
-        let b = u32::from_le_bytes(
-            indirect_data[block_num * 4..block_num * 4 + 4]
-                .try_into()
-                .unwrap(),
-        );
-        if b == 0 {
-            return Err("attemted to write to block 0");
-        }
-        self.get_data_block_mut(b)[start..start + batch].copy_from_slice(&content[..batch]);
-        content = &content[batch..];
-        block_num += 1;
-        while content.len() > 0 {
-            if block_num < bs / 4 {
-                let batch = if content.len() < bs {
-                    content.len() as usize
-                } else {
-                    bs
-                };
-                let indirect_data = self.get_data_block(ind_block_num);
-                let b = u32::from_le_bytes(
-                    indirect_data[block_num * 4..block_num * 4 + 4]
-                        .try_into()
-                        .unwrap(),
-                );
-                if b == 0 {
-                    return Err("attemted to write to block 0");
-                }
-                self.get_data_block_mut(b)[..batch].copy_from_slice(&content[..batch]);
-                content = &content[batch..];
-                block_num += 1;
-            } else {
-                return Ok(total - content.len());
+    pub fn read_link(&self, path: &CStr) -> Result<Vec<u8>, Error> {
+        if let Some(node) = self.find_file(path.to_str().expect("path should be UTF-8")) {
+            if !node.is_symlink() {
+                return Err(Error::Invalid("not a symlink"));
             }
+            return self.read_link_target(&node);
         }
-        Ok(total)
+        Err(Error::NotFound)
     }
 
-    fn write_to_double_indirect_block(
-        &mut self,
-        dob_block_num: u32,
-        content: &[u8],
-        offset: usize,
-    ) -> Result<usize, &'static str> {
-        let bs = (self.sb.block_size * self.sb.block_size / 4) as usize;
-        let total = content.len();
-        let mut block_num = offset / bs;
-        let mut content = content;
-
-        let start = offset % bs;
-        let batch = if content.len() < bs - start {
-            content.len()
-        } else {
-            bs - start
-        };
-        let indirect_data = self.get_data_block(dob_block_num);
-        // println!("{dob_block_num}");
-        // println!("doubly {indirect_data:?}, {offset}, con {}", content.len());
-
-        let b = u32::from_le_bytes(
-            indirect_data[block_num * 4..block_num * 4 + 4]
-                .try_into()
-                .unwrap(),
-        );
-        // println!(" b{b}");
-        if b == 0 {
-            return Err("attemted to write to block 0");
-        }
-        // self.get_data_block_mut(b)[start..start + batch].copy_from_slice(&content[..batch]);
-        self.write_to_indirect_block(b, &content[..batch], start)?;
-        content = &content[batch..];
-        block_num += 1;
-        while content.len() > 0 {
-            if block_num < self.sb.block_size as usize / 4 {
-                let batch = if content.len() < bs {
-                    content.len() as usize
-                } else {
-                    bs
-                };
-                let indirect_data = self.get_data_block(dob_block_num);
-                let b = u32::from_le_bytes(
-                    indirect_data[block_num * 4..block_num * 4 + 4]
-                        .try_into()
-                        .unwrap(),
-                );
-                if b == 0 {
-                    return Err("attemted to write to block 0");
-                }
-                // self.get_data_block_mut(b)[..batch].copy_from_slice(&content[..batch]);
-                self.write_to_indirect_block(b, &content[..batch], 0)?;
-                content = &content[batch..];
-                block_num += 1;
-            } else {
-                return Ok(total - content.len());
-            }
+    fn read_link_target(&self, node: &inode_t) -> Result<Vec<u8>, Error> {
+        if node.is_fast_symlink() {
+            return Ok(node.inline_data()[..node.size as usize].to_vec());
         }
-        Ok(total)
+        Ok(self.get_file_data(node))
     }
 
-    fn write_file_data(
+    /// Creates a regular file, FIFO, socket or device node; `rdev` is only
+    /// kept for character and block devices. A mode without a file type
+    /// makes a regular file.
+    pub fn mknod(
         &mut self,
-        node: &inode_t,
-        content: &[u8],
-        offset: usize,
-    ) -> Result<(), &str> {
-        let mut len = content.len() as isize;
-        let bs = self.sb.block_size as usize;
-        let mut content = content;
-        if offset >= (self.sb.block_size * 12) as usize {
-            if offset >= (self.sb.block_size * (12 + self.sb.block_size / 4)) as usize {
-                // write double indirect
-                self.write_to_double_indirect_block(
-                    node.dob_inblock,
-                    content,
-                    offset - bs * 12 - bs * bs / 4,
-                )
-                .unwrap();
-                return Ok(());
+        path: &CStr,
+        mode: u32,
+        rdev: u32,
+        uid: u16,
+        gid: u16,
+    ) -> Result<(), Error> {
+        let type_perm = match mode as u16 {
+            m if m & 0xF000 == 0 => m | 0x8000,
+            m => m,
+        };
+        match type_perm & 0xF000 {
+            0x8000 | 0x1000 | 0xC000 => {
+                self.create_file_inter(path, &[], type_perm, uid, gid)?;
             }
-            // write indirect
-            let num = self.write_to_indirect_block(node.sin_inblock, content, offset - bs * 12)?;
-            // .expect("attemted to write to block 0");
-            if num != len as usize {
-                self.write_to_double_indirect_block(node.dob_inblock, &content[num..], 0)?;
+            0x2000 | 0x6000 => {
+                let id = self.create_file_inter(path, &[], type_perm, uid, gid)?;
+                let mut node = self.get_inode_by_id(id);
+                node.rdev = rdev;
+                self.save_inode(id, node);
             }
-            return Ok(());
+            _ => return Err(Error::Invalid("unsupported file type")),
         }
-        let mut block_num = offset / bs;
+        Ok(())
+    }
 
-        let start = offset % bs;
-        let batch = if (len as usize) < bs - start {
-            len as usize
-        } else {
-            bs - start
-        };
-        self.get_data_block_mut(node.direct_blocks[block_num])[start..start + batch]
-            .copy_from_slice(&content[..batch]);
-        content = &content[batch..];
-        len -= batch as isize;
-        block_num += 1;
-        while len > 0 {
-            if block_num < 12 {
-                let batch = if (len as usize) < bs {
-                    len as usize
-                } else {
-                    bs
-                };
-                self.get_data_block_mut(node.direct_blocks[block_num])[..batch]
-                    .copy_from_slice(&content[..batch]);
-                content = &content[batch..];
-                len -= batch as isize;
-                block_num += 1;
+    pub fn create_directory(&mut self, path: &CStr, uid: u16, gid: u16) -> Result<(), Error> {
+        let data = vec![0; self.sb.block_size as usize];
+        self.create_file_inter(path, &data, 0x4000 | 0o755, uid, gid)?;
+        Ok(())
+    }
+
+    /// Writes `content` at `offset` of inode `id`, allocating blocks (and
+    /// the indirect blocks leading to them) only where it lands on a hole.
+    fn write_file_data(&mut self, id: inode_p, content: &[u8], offset: usize) -> Result<(), Error> {
+        let bs = self.sb.block_size as usize;
+        let regular = self.get_inode_by_id(id).type_perm & 0xF000 == 0x8000;
+        let mut done = 0;
+        while done < content.len() {
+            let start = (offset + done) % bs;
+            let batch = (bs - start).min(content.len() - done);
+            let b = self.bmap_alloc(id, (offset + done) / bs)?;
+            let block = if regular {
+                self.get_file_block_mut(b)
             } else {
-                let num = self.write_to_indirect_block(node.sin_inblock, content, 0)?;
-                // .expect("attemted to write to block 0");
-                if num != len as usize {
-                    self.write_to_double_indirect_block(
-                        node.dob_inblock,
-                        &content[len as usize - num..],
-                        0,
-                    )?;
-                }
-                panic!("this happened");
-            }
+                self.get_data_block_mut(b)
+            };
+            block[start..start + batch].copy_from_slice(&content[done..done + batch]);
+            done += batch;
         }
-
         Ok(())
     }
 
-    pub fn write_file(&mut self, path: &CStr, content: &[u8], offset: usize) -> i32 {
-        // println!("write {content:?} to offset {offset}");
-        if let Some((node, id)) = self.find_file_mut(path.to_str().unwrap()) {
-            let len = content.len();
-            let size = self.calculate_size(&node);
-            println!("len {}", content.len());
-            if size < len + offset {
-                if self.truncate(path, len + offset).is_err() {
-                    println!("TRUNCATE FAILED");
-                    return -1;
-                }
-                println!("TRUNCATED");
-            }
-            let mut node = self.get_inode_by_id(id);
-            if node.size < (len + offset) as u32 {
-                node.size = (len + offset) as u32;
-                self.save_inode(id, node);
-            }
-            // println!("{:?}", node.direct_blocks);
-            if self.write_file_data(&node, content, offset).is_err() {
-                return 0;
-            }
-            return content.len() as i32;
-        } else {
-            println!("NOT FOUND FILE FOR WRITE");
-            return 0;
+    /// Writes `content` at `offset` of inode `id`, growing the file if it
+    /// ends past the current size.
+    pub fn write_at(&mut self, id: inode_p, content: &[u8], offset: usize) -> Result<usize, Error> {
+        let len = content.len();
+        if len + offset > self.max_file_size() {
+            return Err(Error::TooBig);
+        }
+        // only the written range gets blocks, whatever it skips past the
+        // old end of the file stays a hole
+        let res = self.write_file_data(id, content, offset);
+        let mut node = self.get_inode_by_id(id);
+        if res.is_ok() && node.size < (len + offset) as u64 {
+            node.size = (len + offset) as u64;
+        }
+        node.touch_modified();
+        self.save_inode(id, node);
+        if res.is_err() && node.flags & INODE_PREALLOC == 0 {
+            // blocks the failed write got past the end are out of reach
+            let bs = self.sb.block_size as usize;
+            let _ = self.free_range(id, (node.size as usize).div_ceil(bs), usize::MAX);
         }
+        res.map(|_| len)
     }
 
-    pub fn read_file(&self, path: &CStr) -> Result<Vec<u8>, &str> {
-        if let Some(node) = self.find_file(path.to_str().unwrap()) {
-            // println!(
-            //     "reading from node {:#?} block {}",
-            //     node, node.direct_blocks[0]
-            // );
-            return self.get_file_data(&node);
+    /// Inode number `path` resolves to, following symlinks.
+    pub fn lookup(&self, path: &CStr) -> Option<inode_p> {
+        self.find_file_mut(path.to_str().expect("path should be UTF-8"))
+            .map(|(_, id)| id)
+    }
+
+    /// Inode number behind a file handle handed out by `lookup`, as long as
+    /// that inode is still in use.
+    pub fn check_handle(&self, fh: u64) -> Option<inode_p> {
+        let id = inode_p::try_from(fh).ok()?;
+        if id == 0 || !self.inode_bitmap.is_set(id as usize) {
+            return None;
         }
-        Ok(vec![])
+        self.load_inode(id).ok().map(|_| id)
+    }
+
+    /// Copies the file contents at `offset` into `buf`, mapping only the
+    /// blocks that range covers. Returns how many bytes were read, which is
+    /// less than `buf.len()` at the end of the file.
+    pub fn read_at(&mut self, id: inode_p, offset: usize, buf: &mut [u8]) -> usize {
+        let mut node = self.get_inode_by_id(id);
+        if node.touch_accessed() {
+            self.save_inode(id, node);
+        }
+        if offset >= node.size as usize {
+            return 0;
+        }
+        if node.is_fast_symlink() {
+            let len = buf.len().min(node.size as usize - offset);
+            buf[..len].copy_from_slice(&node.inline_data()[offset..offset + len]);
+            return len;
+        }
+        self.read_data(&node, offset, buf)
     }
 
     fn create_file_inter(
@@ -509,40 +739,57 @@ impl<'a> FileSystem<'a> {
         path: &CStr,
         content: &[u8],
         type_perm: u16,
-    ) -> Result<(), &str> {
+        uid: u16,
+        gid: u16,
+    ) -> Result<inode_p, Error> {
         // if !(path.count_bytes() > 0 && &path.to_str().unwrap()[0..1] == "/") {
         //     return Err("invalid path");
         // }
         // let path = &path[1..];
         let path_str = path.to_str().unwrap();
-        let mut node;
+        let node;
         let node_id;
         let filename;
 
         if let Some(end) = path_str.rfind('/') {
             if end == 0 {
-                node = self.get_inode_by_id(1);
+                node = self.load_inode(1)?;
                 node_id = 1;
                 filename = &path[1..];
             } else {
                 (node, node_id) = self
                     .find_file_mut(&path_str[0..end])
-                    .expect("file not found");
+                    .ok_or(Error::NotFound)?;
                 filename = &path[end + 1..];
             }
         } else {
-            return Err("invalid path");
+            return Err(Error::Invalid("invalid path"));
+        }
+        if !node.is_directory() {
+            return Err(Error::NotDirectory);
         }
 
         if self
-            .search_directory(&mut node, filename.to_str().unwrap())
+            .search_directory(&node, filename.to_str().unwrap())
             .is_some()
         {
-            return Err("file already exists");
+            return Err(Error::Exists);
         }
+        self.check_io()?;
         let name = filename.to_bytes();
 
-        let inode_num = self.inode_bitmap.get_first_free().ok_or("OUT OF MEMORY")?;
+        // setgid directories hand their group (and the bit, to subdirectories) down
+        let (type_perm, gid) = if node.type_perm & 0o2000 != 0 {
+            if type_perm & 0xF000 == 0x4000 {
+                (type_perm | 0o2000, node.gid)
+            } else {
+                (type_perm, node.gid)
+            }
+        } else {
+            (type_perm, gid)
+        };
+
+        let inode_num = self.inode_bitmap.allocate().ok_or(Error::NoSpace)?;
 
         //create dentry
         // if node.direct_blocks[0] == 0 {
@@ -550,50 +797,117 @@ impl<'a> FileSystem<'a> {
         //     println!("occupy block");
         // }
 
-        self.create_dentry(&node, node_id, inode_num as u32, name);
+        let extents =
+            type_perm & 0xF000 == 0x8000 && self.sb.feature_incompat & FEATURE_EXTENTS != 0;
+        let block = if (!content.is_empty() && !extents) || type_perm & 0xF000 == 0x4000 {
+            let Some(block_num) = self.blocks_bitmap.allocate() else {
+                self.inode_bitmap.free(inode_num);
+                return Err(Error::NoSpace);
+            };
+            Some(block_num)
+        } else {
+            None
+        };
+
+        if let Err(e) = self.create_dentry(&node, node_id, inode_num as u32, name) {
+            self.inode_bitmap.free(inode_num);
+            if let Some(block_num) = block {
+                self.blocks_bitmap.free(block_num);
+            }
+            return Err(e);
+        }
 
         //create inode
-        if content.len() > 0 || type_perm & 0x4000 != 0 {
-            let block_num = self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")?;
-            self.create_inode(inode_num, block_num, content.len() as u32, type_perm);
+        if let Some(block_num) = block {
+            self.create_inode(
+                inode_num,
+                block_num,
+                content.len() as u64,
+                type_perm,
+                uid,
+                gid,
+            );
 
             //create data block
             self.get_data_block_mut(block_num as u32)[0..content.len()].copy_from_slice(content);
-            if type_perm & 0x4000 != 0 {
+            if type_perm & 0xF000 == 0x4000 {
                 println!("{:?} created block {}", path, block_num);
                 let mut data = [0u8; 22];
                 data[..4].copy_from_slice(&(inode_num as u32).to_le_bytes());
                 data[4..8].copy_from_slice(&1u32.to_le_bytes());
                 data[8..9].copy_from_slice(".".as_bytes());
 
-                let parent_id = self
-                    .search_directory_get_id(&node, ".")
-                    .expect("parent does not have \".\"");
-                data[12..16].copy_from_slice(&(parent_id as u32).to_le_bytes());
+                data[12..16].copy_from_slice(&node_id.to_le_bytes());
                 data[16..20].copy_from_slice(&2u32.to_le_bytes());
                 data[20..22].copy_from_slice("..".as_bytes());
                 self.get_data_block_mut(block_num as u32)[0..data.len()].copy_from_slice(&data);
+                self.seal_dir_block(block_num as u32);
+
+                // the new ".." links back to the parent
+                let mut parent = self.get_inode_by_id(node_id);
+                parent.hard_links += 1;
+                self.save_inode(node_id, parent);
             }
         } else {
-            self.create_inode(inode_num, 0, content.len() as u32, type_perm);
+            self.create_inode(inode_num, 0, content.len() as u64, type_perm, uid, gid);
+            if extents {
+                let mut file = self.get_inode_by_id(inode_num as inode_p);
+                init_extents(&mut file);
+                self.save_inode(inode_num as inode_p, file);
+            }
+        }
+        let res = if extents {
+            self.write_file_data(inode_num as inode_p, content, 0)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res.and_then(|_| self.inherit_acl(&node, inode_num as inode_p)) {
+            // don't leave a half made file behind
+            let _ = if type_perm & 0xF000 == 0x4000 {
+                self.unlink_dir(path)
+            } else {
+                self.unlink_file(path_str)
+            };
+            return Err(e);
         }
 
-        Ok(())
+        Ok(inode_num as inode_p)
     }
 
-    fn create_dentry(&mut self, node: &inode_t, id: u32, inode_num: u32, name: &[u8]) {
-        let data = self.get_dir_data(&node);
-        let mut node = *node;
-        let offset = if let Some(offset) = FileSystem::find_space_for_dentry(&data, name.len() + 8)
-        {
+    fn create_dentry(
+        &mut self,
+        node: &inode_t,
+        id: u32,
+        inode_num: u32,
+        name: &[u8],
+    ) -> Result<(), Error> {
+        if name.len() > NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        if node.flags & INODE_INDEXED != 0 {
+            self.index_add(id, inode_num, name)?;
+            self.cache_dentry(id, &String::from_utf8_lossy(name), Some(inode_num));
+            let mut node = self.get_inode_by_id(id);
+            node.touch_modified();
+            self.save_inode(id, node);
+            return Ok(());
+        }
+        let data = self.get_dir_data(node);
+        // a block that failed its checksum would look free
+        self.check_io()?;
+        let offset = if let Some(offset) = self.find_space_for_dentry(&data, name.len() + 8) {
             offset
         } else {
-            let size = self.calculate_size(&node);
+            // only directories that fit in one block stay linear
+            if data.len() >= self.sb.block_size as usize && self.build_index(id).is_ok() {
+                let node = self.get_inode_by_id(id);
+                return self.create_dentry(&node, id, inode_num, name);
+            }
+            let size = self.calculate_size(node);
             let mut v = vec![];
             v.extend_from_slice(name);
             println!("ADD NEW BLOCK TO DIR WHEN {:?}", String::from_utf8(v));
-            self.truncate_inter(node, id, (size + 1) as isize).unwrap();
-            node = self.get_inode_by_id(id);
+            self.truncate_inter(*node, id, (size + 1) as isize).unwrap();
             size
         };
 
@@ -604,15 +918,28 @@ impl<'a> FileSystem<'a> {
         dentry.extend_from_slice(&(name_len as u32).to_le_bytes());
         dentry.extend_from_slice(name);
 
-        self.write_file_data(&node, &dentry, offset).unwrap();
+        self.write_file_data(id, &dentry, offset)?;
+        self.seal_dir_range(id, offset, dentry.len());
+        self.cache_dentry(id, &String::from_utf8_lossy(name), Some(inode_num));
+
+        let mut node = self.get_inode_by_id(id);
+        node.touch_modified();
+        self.save_inode(id, node);
+        Ok(())
     }
 
-    fn find_space_for_dentry(data: &[u8], required_size: usize) -> Option<usize> {
+    /// Offset of a run of zeros in directory data where `required_size`
+    /// bytes fit, never reaching into the checksum tail of a block.
+    fn find_space_for_dentry(&self, data: &[u8], required_size: usize) -> Option<usize> {
+        let bs = self.sb.block_size as usize;
+        let room = self.dir_block_room();
         let mut start = 0;
         let mut found = 0;
         let mut i = 0;
         while i + 4 < data.len() {
-            if u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) == 0 {
+            if i % bs >= room {
+                found = 0;
+            } else if u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) == 0 {
                 if found > 0 {
                     found += 4;
                     if found >= required_size {
@@ -631,122 +958,72 @@ impl<'a> FileSystem<'a> {
         None
     }
 
-    pub fn get_files_in_dir(&self, path: &CStr) -> Vec<String> {
+    pub fn get_files_in_dir(&mut self, path: &CStr) -> Vec<String> {
         let mut files = vec![];
-        if let Some(node) = self.find_file(path.to_str().unwrap()) {
+        if let Some((mut node, id)) = self.find_file_mut(path.to_str().unwrap()) {
             if node.is_directory() {
+                if node.touch_accessed() {
+                    self.save_inode(id, node);
+                }
                 let d = self.get_dir_data(&node);
                 let mut data = &d[..];
-                while let Some(dentry) = Dentry::from(data) {
+                while let Some(dentry) = self.dentry(data) {
                     files.push(String::from(dentry.name));
                     data = &data[dentry.size..];
                 }
             }
         }
-        return files;
+        files
     }
 
-    fn read_indirect_block(
-        &self,
-        data: &mut Vec<u8>,
-        block_num: u32,
-        mut size: usize,
-    ) -> Result<usize, &'static str> {
-        let mut indirect = self.get_data_block(block_num);
-        while indirect.len() > 0 {
-            let b = u32::from_le_bytes(indirect[..4].try_into().unwrap());
+    /// Copies what `node` holds at `offset` into `buf`, with holes read as
+    /// zeros, and returns how many bytes were left before the end of file.
+    fn read_data(&self, node: &inode_t, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= node.size as usize {
+            return 0;
+        }
+        let len = buf.len().min(node.size as usize - offset);
+        let bs = self.sb.block_size as usize;
+        let mut done = 0;
+        while done < len {
+            let start = (offset + done) % bs;
+            let (b, run) = self.bmap_run(node, (offset + done) / bs);
+            let batch = (run * bs - start).min(len - done);
             if b != 0 {
-                if size >= self.sb.block_size as usize {
-                    data.extend_from_slice(self.get_data_block(b));
-                    size -= self.sb.block_size as usize;
-                } else {
-                    if size == 0 {
-                        return Err("file has more blocks than it should");
-                    }
-                    data.extend_from_slice(&self.get_data_block(b)[..size]);
-                    size = 0;
-                }
+                buf[done..done + batch]
+                    .copy_from_slice(&self.get_data_blocks(b, run)[start..start + batch]);
             } else {
-                break;
+                buf[done..done + batch].fill(0);
             }
-            indirect = &indirect[4..];
+            done += batch;
         }
-        Ok(size)
+        len
     }
 
-    fn read_double_indirect_block(
-        &self,
-        data: &mut Vec<u8>,
-        block_num: u32,
-        mut size: usize,
-    ) -> Result<usize, &'static str> {
-        let mut indirect = self.get_data_block(block_num);
-        while indirect.len() > 0 {
-            let b = u32::from_le_bytes(indirect[..4].try_into().unwrap());
-            if b != 0 {
-                if size >= self.sb.block_size as usize {
-                    size = self.read_indirect_block(data, b, size)?;
-                } else {
-                    if size == 0 {
-                        return Err("file has more blocks than it should");
-                    }
-                    size = self.read_indirect_block(data, b, size)?;
-                }
-            } else {
-                break;
-            }
-            indirect = &indirect[4..];
-        }
-        Ok(size)
-    }
-    fn get_file_data(&self, node: &inode_t) -> Result<Vec<u8>, &'static str> {
-        let mut data = vec![];
-        let mut size = node.size as usize;
-        let mut blocks = 0;
-        for i in node.direct_blocks {
-            if i != 0 {
-                if size >= self.sb.block_size as usize {
-                    data.extend_from_slice(self.get_data_block(i));
-                    size -= self.sb.block_size as usize;
-                    blocks += 1;
-                } else {
-                    if size == 0 {
-                        println!("{:?}", node);
-                        return Err("file has more blocks than it should");
-                    }
-                    data.extend_from_slice(&self.get_data_block(i)[..size]);
-                    blocks += 1;
-                    size = 0;
-                }
-            }
-        }
-        if node.sin_inblock != 0 {
-            size = self.read_indirect_block(&mut data, node.sin_inblock, size)?;
-        }
-        if node.dob_inblock != 0 {
-            self.read_double_indirect_block(&mut data, node.dob_inblock, size)?;
-        }
-        println!("read from {blocks} BLOCKS");
-        Ok(data)
+    fn get_file_data(&self, node: &inode_t) -> Vec<u8> {
+        let mut data = vec![0; node.size as usize];
+        self.read_data(node, 0, &mut data);
+        data
     }
 
+    /// Every block of a directory; they are allocated one after another and
+    /// its size isn't kept exact, so this goes by the blocks mapped. Tails
+    /// come back as zeros, and so do blocks that fail their checksum.
     fn get_dir_data(&self, node: &inode_t) -> Vec<u8> {
-        let mut data = vec![];
-        for i in node.direct_blocks {
-            if i != 0 {
-                data.extend_from_slice(self.get_data_block(i));
-            }
-        }
-        if node.sin_inblock != 0 {
-            let mut indirect = self.get_data_block(node.sin_inblock);
-            while indirect.len() > 0 {
-                let b = u32::from_le_bytes(indirect[..4].try_into().unwrap());
-                if b != 0 {
-                    data.extend_from_slice(self.get_data_block(b));
-                } else {
-                    break;
-                }
-                indirect = &indirect[4..];
+        let bs = self.sb.block_size as usize;
+        let room = self.dir_block_room();
+        let (blocks, _) = self.count_mapped(node);
+        let mut data = Vec::with_capacity(blocks * bs);
+        for n in 0..blocks {
+            match self.bmap(node, n) {
+                0 => data.resize(data.len() + bs, 0),
+                b => match self.dir_block(b) {
+                    Some(block) => {
+                        data.extend_from_slice(&block[..room]);
+                        data.resize(data.len() + bs - room, 0);
+                    }
+                    None => data.resize(data.len() + bs, 0),
+                },
             }
         }
         data
@@ -829,31 +1106,77 @@ impl<'a> FileSystem<'a> {
         );
     }*/
 
-    fn clear_dentry(&mut self, node: &inode_t, filename: &str) {
+    /// Removes `filename` from directory `node`, numbered `id`. Fails
+    /// without changing anything if its blocks can't be read.
+    fn clear_dentry(&mut self, node: &inode_t, id: inode_p, filename: &str) -> Result<(), Error> {
+        if node.flags & INODE_INDEXED != 0 {
+            if !self.index_remove(node, filename) {
+                // the entry's block may have just failed its checksum
+                self.check_io()?;
+                panic!("tried to delete inexisting entry");
+            }
+            self.cache_dentry(id, filename, None);
+            let mut node = self.get_inode_by_id(id);
+            node.touch_modified();
+            self.save_inode(id, node);
+            return Ok(());
+        }
         let mut i = 0usize;
         let mut data = self.get_dir_data(node);
+        self.check_io()?;
 
         // println!("searching filename {}", filename);
-        while let Some(dentry) = DentryMut::from(&mut data[i..]) {
+        while let Some(dentry) = self.dentry_mut(&mut data[i..]) {
             println!("{i}");
             if dentry.get_name() == filename {
                 // println!("{:?} {} {} {}", dentry.get_name(), filename, i, dentry.size);
-                self.write_file_data(node, &vec![0; dentry.size], i)
-                    .unwrap();
-                // println!("{:?}", self.get_dir_data(node));
-                return;
+                let size = dentry.size;
+                self.write_file_data(id, &vec![0; size], i).unwrap();
+                self.seal_dir_range(id, i, size);
+                self.cache_dentry(id, filename, None);
+                let mut node = self.get_inode_by_id(id);
+                node.touch_modified();
+                self.save_inode(id, node);
+                return Ok(());
             }
             i += dentry.size;
         }
+        self.check_io()?;
         panic!("tried to delete inexisting entry");
     }
 
+    /// Points the ".." entry of directory `id` at `parent`. It is one of the
+    /// first two entries of the directory's first block.
+    fn set_dotdot(&mut self, id: inode_p, parent: inode_p) -> Result<(), Error> {
+        let node = self.load_inode(id)?;
+        let b = self.bmap(&node, 0);
+        let block = self.dir_block(b).ok_or(Error::Io("checksum mismatch"))?;
+        let mut data = block.to_vec();
+        let room = self.dir_block_room();
+        let mut i = 0usize;
+        while let Some(dentry) = self.dentry_mut(&mut data[i..room]) {
+            if dentry.get_name() == ".." {
+                dentry.data[0..4].copy_from_slice(&parent.to_le_bytes());
+                self.get_data_block_mut(b).copy_from_slice(&data);
+                self.seal_dir_block(b);
+                self.forget_dentry(id, "..");
+                return Ok(());
+            }
+            i += dentry.size;
+        }
+        self.check_io()?;
+        panic!("directory does not have \"..\"");
+    }
+
     fn search_directory_get_id(&self, node: &inode_t, filename: &str) -> Option<inode_p> {
+        if node.flags & INODE_INDEXED != 0 {
+            return self.index_lookup(node, filename);
+        }
         let mut i = 0usize;
         let data = self.get_dir_data(node);
 
         //println!("searching filename {}", filename);
-        while let Some(dentry) = Dentry::from(&data[i..]) {
+        while let Some(dentry) = self.dentry(&data[i..]) {
             if dentry.name == filename {
                 //println!("inode num {}", dentry.inode_num);
                 return Some(dentry.inode_num);
@@ -874,195 +1197,193 @@ impl<'a> FileSystem<'a> {
         None
     }
 
+    /// Bytes of data actually allocated to `node`; holes don't count.
     fn calculate_size(&self, node: &inode_t) -> usize {
-        let mut size = 0;
-        for i in node.direct_blocks {
-            if i != 0 {
-                size += self.sb.block_size as usize;
-            } else {
-                break;
-            }
-        }
+        self.count_mapped(node).0 * self.sb.block_size as usize
+    }
 
-        if node.sin_inblock != 0 {
-            let mut indirect = self.get_data_block(node.sin_inblock);
-            while indirect.len() > 0 {
-                let b = u32::from_le_bytes(indirect[..4].try_into().unwrap());
-                if b != 0 {
-                    size += self.sb.block_size as usize;
-                } else {
-                    break;
-                }
-                indirect = &indirect[4..];
-            }
-        }
-        size
+    /// Blocks taken by `node`: its data plus the indirect, index and xattr
+    /// blocks.
+    fn count_blocks(&self, node: &inode_t) -> usize {
+        let (data, indirect) = self.count_mapped(node);
+        data + indirect + self.count_index(node) + (node.xattr_block != 0) as usize
     }
 
-    fn truncate_indirect_block(
-        &mut self,
-        block_num: u32,
-        mut size: isize,
-    ) -> Result<isize, &'static str> {
-        let mut i = 0;
-        // println!(
-        //     "indblock {} {:?}",
-        //     block_num,
-        //     self.get_data_block(block_num)
-        // );
-        while self.get_data_block(block_num).len() - i > 0 {
-            let b =
-                u32::from_le_bytes(self.get_data_block(block_num)[i..i + 4].try_into().unwrap());
-            if size > 0 {
-                if b == 0 {
-                    let free = self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")? as u32;
-                    self.get_data_block_mut(free).zero();
-                    self.get_data_block_mut(block_num)[i..i + 4]
-                        .copy_from_slice(&free.to_le_bytes());
-                }
-                size -= self.sb.block_size as isize;
-            } else {
-                if b != 0 {
-                    self.blocks_bitmap.free(b as usize);
-                    self.get_data_block_mut(block_num)[i..i + 4]
-                        .copy_from_slice(&0u32.to_le_bytes());
-                }
-            }
-            i += 4;
-        }
-        Ok(size)
+    /// Number of 512-byte sectors allocated to the file, as `st_blocks` wants it.
+    pub fn get_blocks(&self, path: &CStr) -> Option<u64> {
+        let node = self.find_file(path.to_str().expect("path should be UTF-8"))?;
+        Some((self.count_blocks(&node) * self.sb.block_size as usize / 512) as u64)
     }
 
-    fn truncate_doubly_indirect_block(
-        &mut self,
-        block_num: u32,
-        mut size: isize,
-    ) -> Result<isize, &'static str> {
-        let mut i = 0;
-        // println!(
-        //     "indblock {} {:?}",
-        //     block_num,
-        //     self.get_data_block(block_num)
-        // );
-        while self.get_data_block(block_num).len() - i > 0 {
-            let mut b =
-                u32::from_le_bytes(self.get_data_block(block_num)[i..i + 4].try_into().unwrap());
-            if size > 0 {
-                if b == 0 {
-                    b = self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")? as u32;
-                    self.get_data_block_mut(b).zero();
-                }
-                // println!("before indirect {size}");
-                size = self.truncate_indirect_block(b, size)?;
-                // println!("after indirect {size}");
-                self.get_data_block_mut(block_num)[i..i + 4].copy_from_slice(&b.to_le_bytes());
-            } else {
-                if b != 0 {
-                    self.truncate_indirect_block(b, size)?;
-                    self.blocks_bitmap.free(b as usize);
-                    self.get_data_block_mut(block_num)[i..i + 4]
-                        .copy_from_slice(&0u32.to_le_bytes());
-                }
-            }
-            i += 4;
-        }
-        Ok(size)
+    /// Largest size addressable through direct, single, double and triple
+    /// indirect blocks.
+    fn max_file_size(&self) -> usize {
+        let bs = self.sb.block_size as usize;
+        let pointers = bs / 4;
+        let blocks = 12 + pointers + pointers * pointers + pointers * pointers * pointers;
+        blocks * bs
     }
 
-    fn truncate_inter(&mut self, mut node: inode_t, id: u32, mut size: isize) -> Result<(), &str> {
-        node.size = size as u32;
-        for i in node.direct_blocks.iter_mut() {
-            // println!("{size} {}", *i);
-            if size > 0 {
-                if *i == 0 {
-                    *i = self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")? as u32;
-                    self.get_data_block_mut(*i).zero();
-                    // println!("{}", *i);
-                }
-                size -= self.sb.block_size as isize;
-            } else {
-                if *i != 0 {
-                    self.blocks_bitmap.free(*i as usize);
-                    *i = 0;
-                }
-            }
+    /// Sets the size of inode `id` and frees every block past it. Growing a
+    /// file allocates nothing, the new range is a hole until written.
+    fn truncate_inter(&mut self, mut node: inode_t, id: u32, size: isize) -> Result<(), Error> {
+        if size as usize > self.max_file_size() {
+            return Err(Error::TooBig);
         }
-        if size > 0 {
-            // create indirect block
-            if node.sin_inblock == 0 {
-                node.sin_inblock =
-                    self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")? as u32;
-                self.get_data_block_mut(node.sin_inblock).zero();
-            }
-            size = self.truncate_indirect_block(node.sin_inblock, size)?;
-            // println!("{:?}", self.get_data_block(node.sin_inblock));
-        } else {
-            // delete indirect block
-            if node.sin_inblock != 0 {
-                self.truncate_indirect_block(node.sin_inblock, size)?;
-                self.blocks_bitmap.free(node.sin_inblock as usize);
-                node.sin_inblock = 0;
+        let size = size as usize;
+        let bs = self.sb.block_size as usize;
+        if (size as u64) < node.size && !size.is_multiple_of(bs) {
+            // the cut off tail has to read back as zeros if the file grows again
+            let b = self.bmap(&node, size / bs);
+            if b != 0 && node.type_perm & 0xF000 == 0x8000 {
+                self.get_file_block_mut(b)[size % bs..].zero();
+            } else if b != 0 {
+                self.get_data_block_mut(b)[size % bs..].zero();
             }
         }
-        if size > 0 {
-            // create double indirect
-            println!("size: {size}");
-            if node.dob_inblock == 0 {
-                node.dob_inblock =
-                    self.blocks_bitmap.get_first_free().ok_or("OUT OF MEMORY")? as u32;
-                self.get_data_block_mut(node.dob_inblock).zero();
-            }
-            size = self
-                .truncate_doubly_indirect_block(node.dob_inblock, size)
-                .unwrap();
-        } else {
-            // delete doubly indirect
-            self.truncate_doubly_indirect_block(node.dob_inblock, size)
-                .unwrap();
-            self.blocks_bitmap.free(node.dob_inblock as usize);
-            node.dob_inblock = 0;
-        }
-        println!("size: {size}");
-        if size > 0 {
-            println!("triple size: {size}");
-            // return Err("triply indirect block");
-            panic!("triply indirect block");
-        }
-        println!("{node:?}");
+        node.size = size as u64;
+        node.flags &= !INODE_PREALLOC;
         self.save_inode(id, node);
-        return Ok(());
+        self.free_range(id, size.div_ceil(bs), usize::MAX)
     }
 
-    pub fn truncate(&mut self, path: &CStr, size: usize) -> Result<(), &str> {
+    pub fn truncate(&mut self, path: &CStr, size: usize) -> Result<(), Error> {
         let path = path.to_str().expect("path should be UTF-8");
-        let size = size as isize;
-        if let Some((node, id)) = self.find_file_mut(path) {
-            return self.truncate_inter(node, id, size);
+        if let Some((_, id)) = self.find_file_mut(path) {
+            return self.truncate_at(id, size);
         }
 
-        Err("failed")
+        Err(Error::NotFound)
     }
 
-    pub fn chmod(&mut self, path: &CStr, mode: u32) -> Result<(), &str> {
+    pub fn truncate_at(&mut self, id: inode_p, size: usize) -> Result<(), Error> {
+        let node = self.get_inode_by_id(id);
+        self.truncate_inter(node, id, size as isize)?;
+        let mut node = self.get_inode_by_id(id);
+        node.touch_modified();
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    pub fn chmod(&mut self, path: &CStr, mode: u32) -> Result<(), Error> {
         if let Some((mut node, id)) =
             self.find_file_mut(path.to_str().expect("path should be UTF-8"))
         {
             node.type_perm = (mode | node.type_perm as u32 & 0xF000) as u16;
+            node.touch_changed();
+            self.save_inode(id, node);
+            self.acl_chmod(id)?;
+            return Ok(());
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Changes owner and group, `None` leaves the id untouched.
+    pub fn chown(&mut self, path: &CStr, uid: Option<u16>, gid: Option<u16>) -> Result<(), Error> {
+        if let Some((mut node, id)) =
+            self.find_file_mut(path.to_str().expect("path should be UTF-8"))
+        {
+            if let Some(uid) = uid {
+                node.uid = uid;
+            }
+            if let Some(gid) = gid {
+                node.gid = gid;
+            }
+            if !node.is_directory() {
+                // drop setuid, and setgid unless it marks mandatory locking
+                node.type_perm &= !0o4000;
+                if node.type_perm & 0o010 != 0 {
+                    node.type_perm &= !0o2000;
+                }
+            }
+            node.touch_changed();
             self.save_inode(id, node);
             return Ok(());
         }
-        Err("file not found")
+        Err(Error::NotFound)
+    }
+
+    /// Sets access and modification times, `None` leaves the time untouched.
+    pub fn set_times(
+        &mut self,
+        path: &CStr,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some((mut node, id)) =
+            self.find_file_mut(path.to_str().expect("path should be UTF-8"))
+        {
+            if let Some(atime) = atime {
+                node.access_time = atime;
+            }
+            if let Some(mtime) = mtime {
+                node.mod_time = mtime;
+            }
+            node.touch_changed();
+            self.save_inode(id, node);
+            return Ok(());
+        }
+        Err(Error::NotFound)
     }
 
     fn get_inode_by_id(&self, id: inode_p) -> inode_t {
         if id == 0 {
             panic!("invalid inode id");
         }
-        let start = (128 * id) as usize;
+        if let Some(node) = self.cached_inode(id) {
+            return node;
+        }
+        let start = 128 * id as usize;
         let data: [u8; 128] = self.inodes[start..start + 128]
             .try_into()
-            .expect(&format!("failed to load inode {}", id));
-        zerocopy::transmute!(data)
+            .unwrap_or_else(|_| panic!("failed to load inode {}", id));
+        let mut node: inode_t = zerocopy::transmute!(data);
+        if self.csum_enabled() && node.checksum != self.inode_csum(id, &node) {
+            self.checksum_failed(&format!("inode {id}"));
+            // nothing of it can be trusted, an empty inode at least leads nowhere
+            return inode_t::new_zeroed();
+        }
+        if self.sb.feature_incompat & FEATURE_LARGE_FILE == 0 {
+            // the high word was padding on images from before 64-bit sizes
+            node.size &= u32::MAX as u64;
+        }
+        self.cache_inode(id, node);
+        node
+    }
+
+    /// `get_inode_by_id` for where a failed checksum has to stop the
+    /// operation before it changes anything.
+    fn load_inode(&self, id: inode_p) -> Result<inode_t, Error> {
+        let node = self.get_inode_by_id(id);
+        self.check_io()?;
+        Ok(node)
+    }
+
+    /// Fails if a read since the operation started hit a bad checksum or a
+    /// mangled entry, whatever it returned then is made up.
+    fn check_io(&self) -> Result<(), Error> {
+        if self.io_error.get() {
+            return Err(Error::Io("checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    /// Directory entry at the start of `data`. A mangled one ends the walk
+    /// like the last entry does, but is recorded as an I/O error.
+    fn dentry<'d>(&self, data: &'d [u8]) -> Option<Dentry<'d>> {
+        Dentry::from(data).unwrap_or_else(|e| {
+            eprintln!("bad directory entry: {e}");
+            self.io_error.set(true);
+            None
+        })
+    }
+
+    fn dentry_mut<'d>(&self, data: &'d mut [u8]) -> Option<DentryMut<'d>> {
+        DentryMut::from(data).unwrap_or_else(|e| {
+            eprintln!("bad directory entry: {e}");
+            self.io_error.set(true);
+            None
+        })
     }
 
     // pub fn get_inode(&mut self, path: &CStr) {
@@ -1071,51 +1392,90 @@ impl<'a> FileSystem<'a> {
     //     self.search_directory(&root, path);
     // }
 
-    pub fn create_inode(&mut self, id: usize, first_block: usize, size: u32, type_perm: u16) {
+    pub fn create_inode(
+        &mut self,
+        id: usize,
+        first_block: usize,
+        size: u64,
+        type_perm: u16,
+        uid: u16,
+        gid: u16,
+    ) {
         // let i = self.inode_bitmap.get_first_free();
         let mut blocks = [0u32; 12];
         blocks[0] = first_block as u32; //self.blocks_bitmap.get_first_free() as u32;
                                         // println!("block {}", blocks[0]);
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went back")
-            .as_secs();
+        let time = current_time();
         //let data: [u8; 128] = .try_into().unwrap();
         let node = inode_t {
             type_perm,
-            uid: 1000,
-            gid: 1000,
-            pad1: 0,
+            uid,
+            gid,
+            flags: 0,
             size,
-            pad2: 0,
             access_time: time,
             mod_time: time,
             creat_time: time,
-            hard_links: 2,
+            // a directory is also linked from its own "."
+            hard_links: if type_perm & 0xF000 == 0x4000 { 2 } else { 1 },
             direct_blocks: blocks,
             sin_inblock: 0,
             dob_inblock: 0,
             tri_inblock: 0,
-            unused: [0i8; 24],
+            rdev: 0,
+            xattr_block: 0,
+            xattr_inline: [0i8; 12],
+            checksum: 0,
         };
-        let data: [u8; 128] = zerocopy::transmute!(node);
-        self.inodes[id * 128..(id + 1) * 128].copy_from_slice(&data);
+        self.write_inode(id as inode_p, node);
+        self.cache_inode(id as inode_p, node);
     }
 
-    fn save_inode(&mut self, id: inode_p, node: inode_t) {
+    fn write_inode(&mut self, id: inode_p, mut node: inode_t) {
+        if self.csum_enabled() {
+            node.checksum = self.inode_csum(id, &node);
+        }
         let id = id as usize;
         let data: [u8; 128] = zerocopy::transmute!(node);
         self.inodes[id * 128..(id + 1) * 128].copy_from_slice(&data);
+        let n = self.journal.inode_table + (id * 128 / self.sb.block_size as usize) as u64;
+        self.journal.touch(n);
+    }
+
+    fn save_inode(&mut self, id: inode_p, node: inode_t) {
+        if node.size > u32::MAX as u64 && self.sb.feature_incompat & FEATURE_LARGE_FILE == 0 {
+            self.sb.feature_incompat |= FEATURE_LARGE_FILE;
+            self.save();
+            // cached sizes were cut to their low word
+            self.forget_inodes();
+        }
+        self.cache_inode(id, node);
+        self.write_inode(id, node);
     }
 
+    /// Data block `id` for writing metadata into, it goes through the journal.
     fn get_data_block_mut(&mut self, id: block_p) -> &mut [u8] {
-        let offset = (id * self.sb.block_size) as usize;
-        return &mut self.data_blocks[offset..offset + self.sb.block_size as usize];
+        self.journal.touch(self.journal.data_area + id as u64);
+        let offset = id as usize * self.sb.block_size as usize;
+        &mut self.data_blocks[offset..offset + self.sb.block_size as usize]
+    }
+
+    /// Data block `id` for writing file contents into, it is written in place.
+    fn get_file_block_mut(&mut self, id: block_p) -> &mut [u8] {
+        self.journal.touch_data(id);
+        let offset = id as usize * self.sb.block_size as usize;
+        &mut self.data_blocks[offset..offset + self.sb.block_size as usize]
+    }
+
+    /// `count` blocks starting at `id`, as one slice.
+    fn get_data_blocks(&self, id: block_p, count: usize) -> &[u8] {
+        let bs = self.sb.block_size as usize;
+        &self.data_blocks[id as usize * bs..(id as usize + count) * bs]
     }
 
     fn get_data_block(&self, id: block_p) -> &[u8] {
-        let offset = (id * self.sb.block_size) as usize;
-        return &self.data_blocks[offset..offset + self.sb.block_size as usize];
+        let offset = id as usize * self.sb.block_size as usize;
+        &self.data_blocks[offset..offset + self.sb.block_size as usize]
     }
 }
 
@@ -1123,35 +1483,148 @@ impl<'a> FileSystem<'a> {
 struct Bitmap<'a> {
     data: &'a mut [u8],
     size: usize,
+    /// Every bit below it is set, so the search for a free one starts here.
+    hint: usize,
+    /// Clear bits, not counting bit 0.
+    free: usize,
+    block_size: usize,
+    /// Blocks of the bitmap changed and bits cleared since the last commit.
+    dirty: BTreeSet<usize>,
+    freed: HashSet<usize>,
 }
 
 impl<'a> Bitmap<'a> {
-    pub fn new(data: &'a mut [u8], size: usize) -> Self {
+    pub fn new(data: &'a mut [u8], size: usize, block_size: usize) -> Self {
         if data.len() * 8 < size {
             panic!("buffer to small to create bitmap");
         }
-        Bitmap { data, size }
+        let mut used: usize = data[..size / 8]
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum();
+        if !size.is_multiple_of(8) {
+            used += (data[size / 8] & ((1 << (size % 8)) - 1)).count_ones() as usize;
+        }
+        // bit 0 is never handed out
+        let free = (size - 1) - (used - (data[0] & 1) as usize);
+        Bitmap {
+            data,
+            size,
+            hint: 1,
+            free,
+            block_size,
+            dirty: BTreeSet::new(),
+            freed: HashSet::new(),
+        }
     }
 
     pub fn take(&mut self, id: usize) {
-        self.data[id / 8] |= 1 << id % 8;
+        if !self.is_set(id) {
+            self.free -= 1;
+        }
+        self.data[id / 8] |= 1 << (id % 8);
+        self.dirty.insert(id / 8 / self.block_size);
         // println!("{:?}", self.data);
     }
 
     pub fn free(&mut self, id: usize) {
-        self.data[id / 8] &= !(1 << id % 8);
+        if self.is_set(id) {
+            self.free += 1;
+        }
+        self.data[id / 8] &= !(1 << (id % 8));
+        self.dirty.insert(id / 8 / self.block_size);
+        self.freed.insert(id);
+        self.hint = self.hint.min(id).max(1);
+    }
+
+    pub fn is_set(&self, id: usize) -> bool {
+        id < self.size && self.data[id / 8] & 1 << (id % 8) != 0
+    }
+
+    /// The 64 bits starting at `id`, which is a multiple of 64.
+    fn word(&self, id: usize) -> u64 {
+        u64::from_le_bytes(self.data[id / 8..id / 8 + 8].try_into().unwrap())
+    }
+
+    /// First clear bit in `from..to`, skipping whole words that are full.
+    fn find_free(&self, from: usize, to: usize) -> Option<usize> {
+        let mut id = from;
+        while id < to {
+            if id.is_multiple_of(64) && id + 64 <= to {
+                let word = self.word(id);
+                if word != u64::MAX {
+                    return Some(id + word.trailing_ones() as usize);
+                }
+                id += 64;
+                continue;
+            }
+            if !self.is_set(id) {
+                return Some(id);
+            }
+            id += 1;
+        }
+        None
+    }
+
+    /// Number of clear bits from `from` on, counting up to `max`.
+    fn free_run(&self, from: usize, max: usize) -> usize {
+        let mut len = 0;
+        while len < max && from + len < self.size {
+            let id = from + len;
+            if id.is_multiple_of(64)
+                && id + 64 <= self.size
+                && max - len >= 64
+                && self.word(id) == 0
+            {
+                len += 64;
+                continue;
+            }
+            if self.is_set(id) {
+                break;
+            }
+            len += 1;
+        }
+        len
+    }
+
+    /// Takes the lowest free bit.
+    pub fn allocate(&mut self) -> Option<usize> {
+        let id = self.find_free(self.hint, self.size)?;
+        self.take(id);
+        self.hint = id + 1;
+        Some(id)
+    }
+
+    /// Takes `goal` if it is free, else the next free bit after it, else the
+    /// lowest free one.
+    pub fn allocate_near(&mut self, goal: usize) -> Option<usize> {
+        if goal <= self.hint || goal >= self.size {
+            return self.allocate();
+        }
+        let id = self
+            .find_free(goal, self.size)
+            .or_else(|| self.find_free(self.hint, goal))?;
+        self.take(id);
+        Some(id)
     }
 
-    pub fn get_first_free(&mut self) -> Option<usize> {
-        let mut i = 1;
-        while i < self.size {
-            if self.data[i / 8] & 1 << i % 8 == 0 {
-                self.data[i / 8] |= 1 << i % 8;
-                return Some(i);
+    /// Takes the lowest run of `n` free bits, returning the first.
+    pub fn allocate_contiguous(&mut self, n: usize) -> Option<usize> {
+        let mut from = self.hint;
+        while let Some(start) = self.find_free(from, self.size) {
+            let len = self.free_run(start, n);
+            if len == n {
+                for id in start..start + n {
+                    self.take(id);
+                }
+                if start == self.hint {
+                    self.hint = start + n;
+                }
+                return Some(start);
             }
-            i += 1;
+            from = start + len;
         }
-        return None;
+        None
     }
 }
 
@@ -1163,8 +1636,10 @@ struct Dentry<'a> {
 }
 
 impl<'a> Dentry<'a> {
-    fn from(data: &'a [u8]) -> Option<Self> {
-        let mut data = &data[..];
+    /// The entry at the start of `data`, skipping zeros, or `None` past the
+    /// last one. Fails on a name that isn't UTF-8.
+    fn from(data: &'a [u8]) -> Result<Option<Self>, &'static str> {
+        let mut data = data;
         let mut i = 0;
         // println!("foo {i} {:?}", &data[0..4]);
         while data.len() >= 8 && u32::from_le_bytes(data[0..4].try_into().unwrap()) == 0 {
@@ -1174,28 +1649,27 @@ impl<'a> Dentry<'a> {
         }
         if data.len() < 8 {
             // println!("too small");
-            return None;
+            return Ok(None);
             //"dentry too small"
         }
         let inode_num = inode_p::from_le_bytes(data[0..4].try_into().unwrap());
         if inode_num == 0 {
             println!("inode 0");
-            return None;
+            return Ok(None);
         }
         let size = u32::from_le_bytes(data[4..8].try_into().unwrap());
         if data.len() < (8 + size) as usize {
             println!("size wrong");
             println!("{} {}", data.len(), 8 + size);
-            return None;
+            return Ok(None);
             //"dir name size incorrect"
         }
-        Some(Self {
+        let Ok(name) = std::str::from_utf8(&data[8..8 + size as usize]) else {
+            return Err("file name isn't UTF-8");
+        };
+        Ok(Some(Self {
             inode_num,
-            name: std::str::from_utf8(&data[8..8 + size as usize]).expect(&format!(
-                "bad file name {:?} size = {}",
-                &data[8..8 + size as usize],
-                size
-            )),
+            name,
             size: match size % 4 {
                 0 => size as usize + 8 + i,
                 1 => size as usize + 8 + i + 3,
@@ -1203,17 +1677,20 @@ impl<'a> Dentry<'a> {
                 3 => size as usize + 8 + i + 1,
                 _ => unreachable!("modulo lol"),
             },
-        })
+        }))
     }
 }
 
 struct DentryMut<'a> {
     size: usize,
+    /// Inode number and name length, the name itself can't be changed.
     data: &'a mut [u8],
+    name: &'a str,
 }
 
 impl<'a> DentryMut<'a> {
-    fn from(data: &'a mut [u8]) -> Option<Self> {
+    /// Like `Dentry::from`.
+    fn from(data: &'a mut [u8]) -> Result<Option<Self>, &'static str> {
         let data = &mut data[..];
         let mut i = 0;
         while data.len() - i >= 8 && u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) == 0 {
@@ -1222,21 +1699,25 @@ impl<'a> DentryMut<'a> {
         }
         if data.len() < 8 {
             println!("too small");
-            return None;
+            return Ok(None);
             //"dentry too small"
         }
         let inode_num = inode_p::from_le_bytes(data[i..i + 4].try_into().unwrap());
         if inode_num == 0 {
             println!("inode 0");
-            return None;
+            return Ok(None);
         }
         let size = u32::from_le_bytes(data[i + 4..i + 8].try_into().unwrap());
         if data.len() < (8 + size) as usize {
             println!("size wrong");
-            return None;
+            return Ok(None);
             //"dir name size incorrect"
         }
-        Some(Self {
+        let (data, name) = data[i..i + 8 + size as usize].split_at_mut(8);
+        let Ok(name) = std::str::from_utf8(name) else {
+            return Err("file name isn't UTF-8");
+        };
+        Ok(Some(Self {
             // size: size as usize + 8,
             size: match size % 4 {
                 0 => size as usize + 8 + i,
@@ -1245,13 +1726,13 @@ impl<'a> DentryMut<'a> {
                 3 => size as usize + 8 + i + 1,
                 _ => unreachable!("modulo lol"),
             },
-            data: &mut data[i..i + 8 + size as usize],
-        })
+            data,
+            name,
+        }))
     }
 
     fn get_name(&self) -> &str {
-        std::str::from_utf8(&self.data[8..])
-            .expect(&format!("bad file name {:?}", &self.data[8..],))
+        self.name
     }
 }
 
@@ -1263,8 +1744,107 @@ pub struct superblock_t {
     pub inodes_num: ::std::os::raw::c_uint,
     pub blocks_num: ::std::os::raw::c_uint,
     pub block_size: ::std::os::raw::c_uint,
+    /// `FEATURE_*` flags a driver has to understand to use the image at all.
+    pub feature_incompat: ::std::os::raw::c_uint,
+    /// Free data blocks and inodes as of the last time the superblock was
+    /// written.
+    pub free_blocks_num: ::std::os::raw::c_uint,
+    pub free_inodes_num: ::std::os::raw::c_uint,
+    /// 0 on images from before the fields below.
+    pub version: ::std::os::raw::c_uint,
+    /// Features drivers that don't know them can ignore.
+    pub feature_compat: ::std::os::raw::c_uint,
+    /// Features drivers that don't know them may read but not write.
+    pub feature_ro_compat: ::std::os::raw::c_uint,
+    /// `STATE_*` flags.
+    pub state: ::std::os::raw::c_ushort,
+    pub mount_count: ::std::os::raw::c_ushort,
+    pub uuid: [::std::os::raw::c_uchar; 16usize],
+    /// Padded with zeros, not terminated when all 16 bytes are used.
+    pub label: [::std::os::raw::c_char; 16usize],
+    pub creat_time: ::std::os::raw::c_ulonglong,
+    pub mount_time: ::std::os::raw::c_ulonglong,
+    pub write_time: ::std::os::raw::c_ulonglong,
+    /// First data block of the journal, with `FEATURE_JOURNAL`.
+    pub journal_block: ::std::os::raw::c_uint,
+    /// CRC-32C of everything above, with `FEATURE_METADATA_CSUM`.
+    pub checksum: ::std::os::raw::c_uint,
 }
 
+pub const SB_SIZE: usize = std::mem::size_of::<superblock_t>();
+
+pub const SB_MAGIC: [::std::os::raw::c_char; 8] = [0x58, 0x44, 0x20, 0x20, 0x20, 0x20, 0x58, 0x44];
+
+/// Superblock layout written by this driver.
+pub const SB_VERSION: u32 = 1;
+
+/// Unmounted properly since the last mount.
+pub const STATE_CLEAN: u16 = 0x1;
+
+/// A checksum failed since the image was last checked.
+pub const STATE_ERRORS: u16 = 0x2;
+
+/// What `df` needs to know about the filesystem.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct statfs_t {
+    pub block_size: ::std::os::raw::c_uint,
+    pub blocks_num: ::std::os::raw::c_uint,
+    pub free_blocks_num: ::std::os::raw::c_uint,
+    pub inodes_num: ::std::os::raw::c_uint,
+    pub free_inodes_num: ::std::os::raw::c_uint,
+    /// Longest file name a directory entry takes.
+    pub name_max: ::std::os::raw::c_uint,
+}
+
+/// Hit and miss counters of the dentry and inode caches.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct cache_stats_t {
+    pub dentry_hits: ::std::os::raw::c_ulonglong,
+    /// Hits on names cached as not existing.
+    pub dentry_negative_hits: ::std::os::raw::c_ulonglong,
+    pub dentry_misses: ::std::os::raw::c_ulonglong,
+    pub inode_hits: ::std::os::raw::c_ulonglong,
+    pub inode_misses: ::std::os::raw::c_ulonglong,
+}
+
+/// Longest file name a directory entry takes.
+pub const NAME_MAX: usize = 255;
+
+/// Inode sizes use all 64 bits; without it only the low word is valid.
+pub const FEATURE_LARGE_FILE: u32 = 0x1;
+
+/// New regular files map their blocks with extents.
+pub const FEATURE_EXTENTS: u32 = 0x2;
+
+/// Metadata changes are logged to a journal before they are written in
+/// place, see `journal.rs`.
+pub const FEATURE_JOURNAL: u32 = 0x4;
+
+const FEATURE_INCOMPAT_SUPPORTED: u32 = FEATURE_LARGE_FILE | FEATURE_EXTENTS | FEATURE_JOURNAL;
+/// Read-only compatible: the superblock, inodes and directory blocks carry
+/// CRC-32C checksums that writers have to keep up to date.
+pub const FEATURE_METADATA_CSUM: u32 = 0x1;
+
+const FEATURE_RO_COMPAT_SUPPORTED: u32 = FEATURE_METADATA_CSUM;
+
+/// Directory entries are found through a hash index rooted at `rdev`.
+pub const INODE_INDEXED: u16 = 0x1;
+/// The block pointer area holds the root of an extent tree.
+pub const INODE_EXTENTS: u16 = 0x2;
+/// `fallocate` left blocks past the end of the file, truncating drops them.
+pub const INODE_PREALLOC: u16 = 0x4;
+
+/// Room for a symlink target in `direct_blocks` and the indirect pointers.
+const SYMLINK_INLINE_MAX: usize = 60;
+/// Longest symlink target, with its terminating zero.
+const PATH_MAX: usize = 4096;
+/// Symlinks followed during a single lookup before giving up.
+const MAX_SYMLINKS: u32 = 40;
+
 #[allow(non_camel_case_types)]
 pub type block_p = ::std::os::raw::c_uint;
 #[allow(non_camel_case_types)]
@@ -1277,9 +1857,10 @@ pub struct inode_t {
     pub type_perm: ::std::os::raw::c_ushort,
     pub uid: ::std::os::raw::c_ushort,
     pub gid: ::std::os::raw::c_ushort,
-    pub pad1: ::std::os::raw::c_ushort,
-    pub size: ::std::os::raw::c_uint,
-    pub pad2: ::std::os::raw::c_uint,
+    /// `INODE_*` flags.
+    pub flags: ::std::os::raw::c_ushort,
+    /// Low word at the offset of the old 32-bit size, high word in what was `pad2`.
+    pub size: ::std::os::raw::c_ulonglong,
     pub access_time: ::std::os::raw::c_ulonglong,
     pub mod_time: ::std::os::raw::c_ulonglong,
     pub creat_time: ::std::os::raw::c_ulonglong,
@@ -1288,11 +1869,248 @@ pub struct inode_t {
     pub sin_inblock: block_p,
     pub dob_inblock: block_p,
     pub tri_inblock: block_p,
-    pub unused: [::std::os::raw::c_char; 24usize],
+    /// Device number of character and block devices, packed like Linux
+    /// does with 12 bits of major and 20 of minor; root block of the hash
+    /// index of directories with `INODE_INDEXED` set.
+    pub rdev: ::std::os::raw::c_uint,
+    pub xattr_block: block_p,
+    /// `XATTR_INLINE_SIZE` bytes of attributes that skip the xattr block.
+    pub xattr_inline: [::std::os::raw::c_char; 12usize],
+    /// CRC-32C of the inode number and the bytes above, with
+    /// `FEATURE_METADATA_CSUM`.
+    pub checksum: ::std::os::raw::c_uint,
+}
+
+/// Random version 4 UUID, seeded by the randomly keyed std hasher.
+fn new_uuid() -> [u8; 16] {
+    let mut uuid = [0; 16];
+    for (i, half) in uuid.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(current_time());
+        hasher.write_usize(i);
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+    uuid
+}
+
+pub(crate) fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went back")
+        .as_secs()
 }
 
 impl inode_t {
+    /// Content changed: bumps mtime and ctime (`creat_time` is reported as ctime).
+    pub fn touch_modified(&mut self) {
+        let time = current_time();
+        self.mod_time = time;
+        self.creat_time = time;
+    }
+
+    /// Metadata changed: bumps ctime only.
+    pub fn touch_changed(&mut self) {
+        self.creat_time = current_time();
+    }
+
+    /// Relatime-style atime update, returns whether the inode needs saving.
+    pub fn touch_accessed(&mut self) -> bool {
+        let time = current_time();
+        if self.access_time <= self.mod_time
+            || self.access_time <= self.creat_time
+            || time.saturating_sub(self.access_time) >= 24 * 60 * 60
+        {
+            self.access_time = time;
+            return true;
+        }
+        false
+    }
+
     pub fn is_directory(&self) -> bool {
-        self.type_perm & 0x4000 != 0
+        self.type_perm & 0xF000 == 0x4000
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.type_perm & 0xF000 == 0xA000
+    }
+
+    /// Short symlink targets are kept in the block pointer area instead of
+    /// a data block.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.size as usize <= SYMLINK_INLINE_MAX
+    }
+
+    fn inline_data(&self) -> [u8; SYMLINK_INLINE_MAX] {
+        let start = offset_of!(inode_t, direct_blocks);
+        let data: [u8; 128] = zerocopy::transmute!(*self);
+        data[start..start + SYMLINK_INLINE_MAX].try_into().unwrap()
+    }
+
+    fn set_inline_data(&mut self, content: &[u8]) {
+        let start = offset_of!(inode_t, direct_blocks);
+        let mut data: [u8; 128] = zerocopy::transmute!(*self);
+        data[start..start + SYMLINK_INLINE_MAX].fill(0);
+        data[start..start + content.len()].copy_from_slice(content);
+        *self = zerocopy::transmute!(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::Image;
+
+    /// Bits set in a fixed pseudo-random pattern, with whole words full and
+    /// whole words empty in between.
+    fn pattern() -> Vec<u8> {
+        let mut x = 1u32;
+        let mut data: Vec<u8> = (0..128)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8 | (x >> 24) as u8
+            })
+            .collect();
+        data[16..40].fill(0xff);
+        data[64..80].fill(0);
+        data
+    }
+
+    #[test]
+    fn holes_read_back_as_zeros() {
+        let img = Image::format("holes", 1024, 2000, 256);
+        img.mount(|fs| {
+            for (path, extents) in [(c"/blocks", false), (c"/extents", true)] {
+                if extents {
+                    fs.sb.feature_incompat |= FEATURE_EXTENTS;
+                } else {
+                    fs.sb.feature_incompat &= !FEATURE_EXTENTS;
+                }
+                fs.create_file(path, &[], 0x8000 | 0o644, 0, 0).unwrap();
+                let id = fs.lookup(path).unwrap();
+                let free = fs.blocks_bitmap.free;
+                fs.write_at(id, b"head", 0).unwrap();
+                fs.write_at(id, b"tail", 100 * 1024).unwrap();
+                // growing it leaves another hole at the end
+                fs.truncate_at(id, 200 * 1024).unwrap();
+                let node = fs.get_inode_by_id(id);
+                assert_eq!(node.size, 200 * 1024);
+                // the single indirect block maps the tail without extents
+                let (data, indirect) = fs.count_mapped(&node);
+                assert_eq!((data, indirect), (2, !extents as usize));
+                assert_eq!(fs.blocks_bitmap.free, free - data - indirect);
+
+                let mut buf = vec![0xff; 200 * 1024];
+                assert_eq!(fs.read_at(id, 0, &mut buf), buf.len());
+                assert_eq!(&buf[..4], b"head");
+                assert_eq!(&buf[100 * 1024..100 * 1024 + 4], b"tail");
+                buf[..4].fill(0);
+                buf[100 * 1024..100 * 1024 + 4].fill(0);
+                assert!(buf.iter().all(|b| *b == 0));
+            }
+            assert!(fs.check().is_empty());
+        });
+    }
+
+    #[test]
+    fn sizes_past_4_gib() {
+        const GIB: usize = 1 << 30;
+        let img = Image::format("large", 1024, 2000, 256);
+        img.mount(|fs| {
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            let id = fs.lookup(c"/f").unwrap();
+            assert_eq!(fs.write_at(id, b"end", 5 * GIB).unwrap(), 3);
+            fs.unmount().unwrap();
+        });
+        img.mount(|fs| {
+            let id = fs.lookup(c"/f").unwrap();
+            assert_eq!(fs.get_attr(c"/f").unwrap().size, 5 * GIB as u64 + 3);
+            let mut buf = [0xff; 4];
+            assert_eq!(fs.read_at(id, 5 * GIB - 1, &mut buf), 4);
+            assert_eq!(&buf, b"\0end");
+            assert_eq!(fs.read_at(id, 4 * GIB, &mut buf), 4);
+            assert_eq!(buf, [0; 4]);
+
+            let free = fs.blocks_bitmap.free;
+            fs.truncate_at(id, 4 * GIB + 1).unwrap();
+            let node = fs.get_inode_by_id(id);
+            assert_eq!(node.size, 4 * GIB as u64 + 1);
+            assert_eq!(fs.read_at(id, 4 * GIB, &mut buf), 1);
+            assert_eq!(fs.count_mapped(&node).0, 0);
+            assert_eq!(fs.blocks_bitmap.free, free + 1);
+            assert!(fs.check().is_empty());
+        });
+    }
+
+    #[test]
+    fn bad_images_are_refused() {
+        assert_eq!(
+            FileSystem::new(&mut [0; 100]).err(),
+            Some(Error::Invalid("image too small"))
+        );
+        assert_eq!(
+            FileSystem::new(&mut [0; 4096]).err(),
+            Some(Error::Invalid("not an oxidizedFS image"))
+        );
+        assert!(matches!(
+            FileSystem::check_geometry(1000, 100, 10),
+            Err(Error::Invalid(_))
+        ));
+
+        let img = Image::format("bad-sb", 1024, 2000, 256);
+        let mut sb = img.read(0, SB_SIZE);
+        sb[offset_of!(superblock_t, label)] ^= 1;
+        img.write(0, &sb);
+        assert!(matches!(img.open(|_| ()), Err(Error::Io(_))));
+        sb[offset_of!(superblock_t, label)] ^= 1;
+        sb[offset_of!(superblock_t, feature_incompat)] |= 0x80;
+        let csum = crc32c(0, &sb[..SB_SIZE - 4]);
+        sb[SB_SIZE - 4..].copy_from_slice(&csum.to_le_bytes());
+        img.write(0, &sb);
+        assert!(matches!(img.open(|_| ()), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn word_scan_matches_bit_by_bit() {
+        let mut data = pattern();
+        let size = 1000;
+        let bitmap = Bitmap::new(&mut data, size, 1024);
+        let clear = (1..size).filter(|i| !bitmap.is_set(*i)).count();
+        assert_eq!(bitmap.free, clear);
+        for from in 0..size {
+            for to in (from..=size).step_by(7) {
+                let slow = (from..to).find(|i| !bitmap.is_set(*i));
+                assert_eq!(bitmap.find_free(from, to), slow, "{from}..{to}");
+            }
+            for max in [1, 5, 64, 100, 1000] {
+                let slow = (from..size)
+                    .take(max)
+                    .take_while(|i| !bitmap.is_set(*i))
+                    .count();
+                assert_eq!(bitmap.free_run(from, max), slow, "{from} {max}");
+            }
+        }
+    }
+
+    #[test]
+    fn allocation_follows_the_hint() {
+        let mut data = vec![0; 16];
+        let mut bitmap = Bitmap::new(&mut data, 128, 1024);
+        assert_eq!(bitmap.free, 127);
+        assert_eq!(bitmap.allocate(), Some(1));
+        assert_eq!(bitmap.allocate(), Some(2));
+        assert_eq!(bitmap.allocate_contiguous(70), Some(3));
+        assert_eq!(bitmap.hint, 73);
+        assert_eq!(bitmap.allocate_near(100), Some(100));
+        assert_eq!(bitmap.allocate_near(100), Some(101));
+        bitmap.free(2);
+        assert_eq!(bitmap.hint, 2);
+        assert_eq!(bitmap.allocate(), Some(2));
+        assert_eq!(bitmap.allocate(), Some(73));
+        // the gap below 100 takes exactly 26, past 101 there are only 26 more
+        assert_eq!(bitmap.allocate_contiguous(26), Some(74));
+        assert_eq!(bitmap.allocate_contiguous(27), None);
+        assert_eq!(bitmap.free, 127 - 101);
     }
 }
@@ -0,0 +1,284 @@
+use std::ffi::CStr;
+
+use super::{inode_p, inode_t, Error, FileSystem};
+
+/// POSIX ACLs live in these extended attributes, in the same binary format
+/// the kernel uses for them, so getfacl/setfacl work through FUSE as is.
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Identity of the caller an access check is made for.
+#[derive(Debug, Clone)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups.
+    pub groups: Vec<u32>,
+}
+
+impl Cred {
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+/// Decodes an ACL xattr value, rejecting malformed or incomplete ACLs.
+pub fn parse_acl(data: &[u8]) -> Option<Vec<AclEntry>> {
+    if data.len() < 4 || !(data.len() - 4).is_multiple_of(8) {
+        return None;
+    }
+    if u32::from_le_bytes(data[..4].try_into().unwrap()) != ACL_VERSION {
+        return None;
+    }
+    let entries: Vec<AclEntry> = data[4..]
+        .chunks(8)
+        .map(|e| AclEntry {
+            tag: u16::from_le_bytes(e[0..2].try_into().unwrap()),
+            perm: u16::from_le_bytes(e[2..4].try_into().unwrap()),
+            id: u32::from_le_bytes(e[4..8].try_into().unwrap()),
+        })
+        .collect();
+
+    let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+    if count(ACL_USER_OBJ) != 1 || count(ACL_GROUP_OBJ) != 1 || count(ACL_OTHER) != 1 {
+        return None;
+    }
+    if count(ACL_MASK) > 1 || (count(ACL_MASK) == 0 && count(ACL_USER) + count(ACL_GROUP) > 0) {
+        return None;
+    }
+    if entries.iter().any(|e| {
+        e.perm > 7
+            || !matches!(
+                e.tag,
+                ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER
+            )
+    }) {
+        return None;
+    }
+    Some(entries)
+}
+
+pub fn serialize_acl(entries: &[AclEntry]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + entries.len() * 8);
+    data.extend_from_slice(&ACL_VERSION.to_le_bytes());
+    for e in entries {
+        data.extend_from_slice(&e.tag.to_le_bytes());
+        data.extend_from_slice(&e.perm.to_le_bytes());
+        data.extend_from_slice(&e.id.to_le_bytes());
+    }
+    data
+}
+
+fn find_tag(entries: &[AclEntry], tag: u16) -> Option<&AclEntry> {
+    entries.iter().find(|e| e.tag == tag)
+}
+
+/// Permission bits matching the owner, group class and other entries.
+pub fn acl_mode(entries: &[AclEntry]) -> u16 {
+    let user = find_tag(entries, ACL_USER_OBJ).map_or(0, |e| e.perm);
+    let group = find_tag(entries, ACL_MASK)
+        .or(find_tag(entries, ACL_GROUP_OBJ))
+        .map_or(0, |e| e.perm);
+    let other = find_tag(entries, ACL_OTHER).map_or(0, |e| e.perm);
+    user << 6 | group << 3 | other
+}
+
+/// An ACL with only the three base entries says nothing the mode doesn't.
+pub fn acl_is_minimal(entries: &[AclEntry]) -> bool {
+    entries.len() == 3
+}
+
+/// Rewrites the owner, group class and other entries from `mode`.
+fn acl_chmod_entries(entries: &mut [AclEntry], mode: u16) {
+    let has_mask = find_tag(entries, ACL_MASK).is_some();
+    for e in entries.iter_mut() {
+        match e.tag {
+            ACL_USER_OBJ => e.perm = mode >> 6 & 7,
+            ACL_MASK => e.perm = mode >> 3 & 7,
+            ACL_GROUP_OBJ if !has_mask => e.perm = mode >> 3 & 7,
+            ACL_OTHER => e.perm = mode & 7,
+            _ => {}
+        }
+    }
+}
+
+/// Restricts an inherited ACL to the permissions requested at creation and
+/// returns the resulting permission bits.
+fn acl_create_entries(entries: &mut [AclEntry], mode: u16) -> u16 {
+    let has_mask = find_tag(entries, ACL_MASK).is_some();
+    for e in entries.iter_mut() {
+        match e.tag {
+            ACL_USER_OBJ => e.perm &= mode >> 6 & 7,
+            ACL_MASK => e.perm &= mode >> 3 & 7,
+            ACL_GROUP_OBJ if !has_mask => e.perm &= mode >> 3 & 7,
+            ACL_OTHER => e.perm &= mode & 7,
+            _ => {}
+        }
+    }
+    acl_mode(entries)
+}
+
+impl<'a> FileSystem<'a> {
+    fn get_acl(&self, node: &inode_t, name: &str) -> Option<Vec<AclEntry>> {
+        self.read_xattrs(node)
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, value)| parse_acl(&value))
+    }
+
+    /// Checks `mask` (4 read, 2 write, 1 execute/search) against the ACL of
+    /// `node`, falling back to the mode bits when it has none.
+    pub(super) fn permission(&self, node: &inode_t, cred: &Cred, mask: u16) -> bool {
+        if cred.uid == 0 {
+            // root may do anything but execute files nobody can execute
+            return mask & 1 == 0 || node.is_directory() || node.type_perm & 0o111 != 0;
+        }
+        let granted = |perm: u16| perm & mask == mask;
+        let mode = node.type_perm;
+        let Some(acl) = self.get_acl(node, ACL_ACCESS) else {
+            if cred.uid == node.uid as u32 {
+                return granted(mode >> 6 & 7);
+            }
+            if cred.in_group(node.gid as u32) {
+                return granted(mode >> 3 & 7);
+            }
+            return granted(mode & 7);
+        };
+
+        let acl_mask = find_tag(&acl, ACL_MASK).map_or(7, |e| e.perm);
+        if cred.uid == node.uid as u32 {
+            return find_tag(&acl, ACL_USER_OBJ).is_some_and(|e| granted(e.perm));
+        }
+        if let Some(e) = acl.iter().find(|e| e.tag == ACL_USER && e.id == cred.uid) {
+            return granted(e.perm & acl_mask);
+        }
+        let mut group_matched = false;
+        for e in &acl {
+            let matches = match e.tag {
+                ACL_GROUP_OBJ => cred.in_group(node.gid as u32),
+                ACL_GROUP => cred.in_group(e.id),
+                _ => false,
+            };
+            if matches {
+                if granted(e.perm & acl_mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+        find_tag(&acl, ACL_OTHER).is_some_and(|e| granted(e.perm))
+    }
+
+    /// Applies the default ACL of `parent` to the freshly created inode `id`.
+    pub(super) fn inherit_acl(&mut self, parent: &inode_t, id: inode_p) -> Result<(), Error> {
+        let Some(default) = self.get_acl(parent, ACL_DEFAULT) else {
+            return Ok(());
+        };
+        let mut node = self.get_inode_by_id(id);
+        if node.is_symlink() {
+            return Ok(());
+        }
+        let mut access = default.clone();
+        let perm = acl_create_entries(&mut access, node.type_perm & 0o777);
+        node.type_perm = node.type_perm & !0o777 | perm;
+
+        let mut attrs = self.read_xattrs(&node);
+        if !acl_is_minimal(&access) {
+            attrs.push((ACL_ACCESS.to_string(), serialize_acl(&access)));
+        }
+        if node.is_directory() {
+            attrs.push((ACL_DEFAULT.to_string(), serialize_acl(&default)));
+        }
+        self.write_xattrs(id, node, &attrs)
+    }
+
+    /// Keeps the access ACL of `id` in line with its mode after a chmod.
+    pub(super) fn acl_chmod(&mut self, id: inode_p) -> Result<(), Error> {
+        let node = self.get_inode_by_id(id);
+        let Some(mut acl) = self.get_acl(&node, ACL_ACCESS) else {
+            return Ok(());
+        };
+        acl_chmod_entries(&mut acl, node.type_perm & 0o777);
+        let mut attrs = self.read_xattrs(&node);
+        for (name, value) in attrs.iter_mut() {
+            if name == ACL_ACCESS {
+                *value = serialize_acl(&acl);
+            }
+        }
+        self.write_xattrs(id, node, &attrs)
+    }
+
+    /// Resolves `path` as `cred`, requiring search permission on every
+    /// directory on the way, and checks `mask` on the file it names.
+    pub fn access(&self, path: &CStr, cred: &Cred, mask: u16) -> Result<(), Error> {
+        let path = path.to_str().expect("path should be UTF-8");
+        let (node, _) = self.resolve(path, Some(cred))?;
+        if self.permission(&node, cred, mask) {
+            return Ok(());
+        }
+        Err(Error::Access)
+    }
+
+    /// Same as `access`, for the directory holding `path`. Creating and
+    /// removing entries needs write and search permission there.
+    pub fn access_parent(&self, path: &CStr, cred: &Cred, mask: u16) -> Result<(), Error> {
+        let path = path.to_str().expect("path should be UTF-8");
+        let parent = match path.rfind('/') {
+            Some(0) => "/",
+            Some(offset) => &path[..offset],
+            None => return Err(Error::Invalid("bad filename format")),
+        };
+        let (dir, _) = self.resolve(parent, Some(cred))?;
+        if !dir.is_directory() {
+            return Err(Error::NotDirectory);
+        }
+        if self.permission(&dir, cred, mask) {
+            return Ok(());
+        }
+        Err(Error::Access)
+    }
+
+    /// Checks that `cred` may change the mode of `path`, or give it owner
+    /// `uid` and group `gid`. Root may do anything, the owner only hand
+    /// the file to one of its own groups.
+    pub fn check_owner(
+        &self,
+        path: &CStr,
+        cred: &Cred,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), Error> {
+        let path = path.to_str().expect("path should be UTF-8");
+        let (node, _) = self.resolve(path, Some(cred))?;
+        if cred.uid == 0 {
+            return Ok(());
+        }
+        let owner = node.uid as u32;
+        if cred.uid != owner
+            || uid.is_some_and(|uid| uid != owner)
+            || gid.is_some_and(|gid| gid != node.gid as u32 && !cred.in_group(gid))
+        {
+            return Err(Error::NotPermitted);
+        }
+        Ok(())
+    }
+}
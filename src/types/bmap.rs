@@ -0,0 +1,376 @@
+use zerocopy::FromZeros;
+
+use super::{block_p, inode_p, inode_t, Error, FileSystem, INODE_EXTENTS};
+
+/// Index of the single, double and triple indirect pointers when the 15 block
+/// pointers of an inode are numbered in order after the 12 direct ones.
+const SIN_SLOT: usize = 12;
+const DOB_SLOT: usize = 13;
+const TRI_SLOT: usize = 14;
+
+fn slot(node: &inode_t, slot: usize) -> block_p {
+    match slot {
+        SIN_SLOT => node.sin_inblock,
+        DOB_SLOT => node.dob_inblock,
+        TRI_SLOT => node.tri_inblock,
+        _ => node.direct_blocks[slot],
+    }
+}
+
+fn slot_mut(node: &mut inode_t, slot: usize) -> &mut block_p {
+    match slot {
+        SIN_SLOT => &mut node.sin_inblock,
+        DOB_SLOT => &mut node.dob_inblock,
+        TRI_SLOT => &mut node.tri_inblock,
+        _ => &mut node.direct_blocks[slot],
+    }
+}
+
+impl<'a> FileSystem<'a> {
+    fn pointers(&self) -> usize {
+        self.sb.block_size as usize / 4
+    }
+
+    /// First logical block, levels of indirection and number of logical
+    /// blocks mapped through inode pointer `slot`.
+    fn slot_range(&self, slot: usize) -> (usize, u32, usize) {
+        let p = self.pointers();
+        match slot {
+            SIN_SLOT => (12, 1, p),
+            DOB_SLOT => (12 + p, 2, p * p),
+            TRI_SLOT => (12 + p + p * p, 3, p * p * p),
+            _ => (slot, 0, 1),
+        }
+    }
+
+    /// Inode pointer and indirect block entries leading to logical block `n`,
+    /// or `None` past the triple indirect block.
+    fn bmap_path(&self, n: usize) -> Option<(usize, u32, [usize; 3])> {
+        let p = self.pointers();
+        (0..=TRI_SLOT).rev().find_map(|slot| {
+            let (first, depth, len) = self.slot_range(slot);
+            if n < first || n - first >= len {
+                return None;
+            }
+            let rel = n - first;
+            let mut indices = [0; 3];
+            for (level, index) in indices.iter_mut().take(depth as usize).enumerate() {
+                *index = rel / p.pow(depth - 1 - level as u32) % p;
+            }
+            Some((slot, depth, indices))
+        })
+    }
+
+    pub(super) fn get_entry(&self, block_num: block_p, index: usize) -> block_p {
+        u32::from_le_bytes(
+            self.get_data_block(block_num)[index * 4..index * 4 + 4]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// Takes a free block and zeroes it, so the parts of it that never get
+    /// written read back the same as a hole.
+    pub(super) fn alloc_zeroed_block(&mut self) -> Result<block_p, Error> {
+        self.alloc_zeroed_block_near(0)
+    }
+
+    /// Like `alloc_zeroed_block`, preferring block `goal` or the closest
+    /// free one after it.
+    pub(super) fn alloc_zeroed_block_near(&mut self, goal: usize) -> Result<block_p, Error> {
+        let b = self
+            .blocks_bitmap
+            .allocate_near(goal)
+            .ok_or(Error::NoSpace)? as block_p;
+        // whatever gets written into it next decides if it goes through the journal
+        self.get_file_block_mut(b).zero();
+        Ok(b)
+    }
+
+    /// Returns entry `index` of indirect block `block_num`, allocating the
+    /// block it points to if it is still a hole.
+    fn get_or_alloc_entry(
+        &mut self,
+        block_num: block_p,
+        index: usize,
+        goal: usize,
+    ) -> Result<block_p, Error> {
+        let b = self.get_entry(block_num, index);
+        if b != 0 {
+            return Ok(b);
+        }
+        let b = self.alloc_zeroed_block_near(goal)?;
+        self.get_data_block_mut(block_num)[index * 4..index * 4 + 4]
+            .copy_from_slice(&b.to_le_bytes());
+        Ok(b)
+    }
+
+    /// Physical block holding logical block `n` of `node`, 0 for a hole.
+    pub(super) fn bmap(&self, node: &inode_t, n: usize) -> block_p {
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_map(node, n).0;
+        }
+        let Some((s, depth, indices)) = self.bmap_path(n) else {
+            return 0;
+        };
+        let mut b = slot(node, s);
+        for index in &indices[..depth as usize] {
+            if b == 0 {
+                return 0;
+            }
+            b = self.get_entry(b, *index);
+        }
+        b
+    }
+
+    /// Like `bmap`, also returning how many blocks from there on are
+    /// physically contiguous, so they can be copied in one go.
+    pub(super) fn bmap_run(&self, node: &inode_t, n: usize) -> (block_p, usize) {
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_map(node, n);
+        }
+        (self.bmap(node, n), 1)
+    }
+
+    /// Like `bmap`, but fills a hole (and the indirect blocks leading to it)
+    /// with freshly zeroed blocks, saving inode `id` if its pointers change.
+    /// New blocks go right after the one mapping `n - 1` when possible.
+    pub(super) fn bmap_alloc(&mut self, id: inode_p, n: usize) -> Result<block_p, Error> {
+        let mut node = self.get_inode_by_id(id);
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_alloc(id, n);
+        }
+        let (s, depth, indices) = self.bmap_path(n).ok_or(Error::TooBig)?;
+        let goal = match n {
+            0 => 0,
+            _ => self.bmap(&node, n - 1) as usize + 1,
+        };
+        let mut b = slot(&node, s);
+        if b == 0 {
+            b = self.alloc_zeroed_block_near(goal)?;
+            *slot_mut(&mut node, s) = b;
+            self.save_inode(id, node);
+        }
+        for index in &indices[..depth as usize] {
+            b = self.get_or_alloc_entry(b, *index, goal)?;
+        }
+        Ok(b)
+    }
+
+    /// Fills holes from logical block `n` on, at most `count` of them, and
+    /// returns how many got blocks. Extent files get them as one run.
+    pub(super) fn bmap_alloc_run(
+        &mut self,
+        id: inode_p,
+        n: usize,
+        count: usize,
+    ) -> Result<usize, Error> {
+        if self.get_inode_by_id(id).flags & INODE_EXTENTS != 0 {
+            return self.ext_alloc_run(id, n, count);
+        }
+        self.bmap_alloc(id, n).map(|_| 1)
+    }
+
+    /// Frees the blocks mapping `first..last` below indirect block `block_num`,
+    /// numbered from the start of the range it covers. Returns whether the
+    /// indirect block no longer maps anything.
+    fn free_indirect_range(
+        &mut self,
+        block_num: block_p,
+        depth: u32,
+        first: usize,
+        last: usize,
+    ) -> bool {
+        let p = self.pointers();
+        let span = p.pow(depth - 1);
+        for i in 0..p {
+            let start = i * span;
+            if start >= last || start + span <= first {
+                continue;
+            }
+            let b = self.get_entry(block_num, i);
+            if b == 0 {
+                continue;
+            }
+            if depth == 1
+                || self.free_indirect_range(b, depth - 1, first.saturating_sub(start), last - start)
+            {
+                self.blocks_bitmap.free(b as usize);
+                self.get_data_block_mut(block_num)[i * 4..i * 4 + 4].zero();
+            }
+        }
+        self.get_data_block(block_num).iter().all(|b| *b == 0)
+    }
+
+    /// Turns logical blocks `first..last` of inode `id` into holes, releasing
+    /// the indirect blocks that are left without entries. Only an extent
+    /// tree can run out of space doing so.
+    pub(super) fn free_range(
+        &mut self,
+        id: inode_p,
+        first: usize,
+        last: usize,
+    ) -> Result<(), Error> {
+        let mut node = self.get_inode_by_id(id);
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_free_range(id, first, last);
+        }
+        for s in 0..=TRI_SLOT {
+            let (start, depth, len) = self.slot_range(s);
+            let b = slot(&node, s);
+            if b == 0 || start >= last || start + len <= first {
+                continue;
+            }
+            if depth == 0
+                || self.free_indirect_range(b, depth, first.saturating_sub(start), last - start)
+            {
+                self.blocks_bitmap.free(b as usize);
+                *slot_mut(&mut node, s) = 0;
+            }
+        }
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    fn count_indirect(&self, block_num: block_p, depth: u32) -> (usize, usize) {
+        let (mut data, mut indirect) = (0, 1);
+        for i in 0..self.pointers() {
+            let b = self.get_entry(block_num, i);
+            if b == 0 {
+                continue;
+            }
+            if depth == 1 {
+                data += 1;
+            } else {
+                let (d, ind) = self.count_indirect(b, depth - 1);
+                data += d;
+                indirect += ind;
+            }
+        }
+        (data, indirect)
+    }
+
+    /// Number of data blocks and of indirect (or extent tree) blocks mapped
+    /// by `node`.
+    pub(super) fn count_mapped(&self, node: &inode_t) -> (usize, usize) {
+        if node.is_fast_symlink() {
+            return (0, 0);
+        }
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_count(node);
+        }
+        let (mut data, mut indirect) = (0, 0);
+        for s in 0..=TRI_SLOT {
+            let b = slot(node, s);
+            let (_, depth, _) = self.slot_range(s);
+            if b == 0 {
+                continue;
+            }
+            if depth == 0 {
+                data += 1;
+            } else {
+                let (d, ind) = self.count_indirect(b, depth);
+                data += d;
+                indirect += ind;
+            }
+        }
+        (data, indirect)
+    }
+
+    fn visit_indirect(
+        &self,
+        block_num: block_p,
+        depth: u32,
+        first: usize,
+        visit: &mut dyn FnMut(Option<usize>, block_p) -> bool,
+    ) {
+        let span = self.pointers().pow(depth - 1);
+        for i in 0..self.pointers() {
+            let b = self.get_entry(block_num, i);
+            if b == 0 {
+                continue;
+            }
+            if depth == 1 {
+                visit(Some(first + i), b);
+            } else if visit(None, b) {
+                self.visit_indirect(b, depth - 1, first + i * span, visit);
+            }
+        }
+    }
+
+    /// Calls `visit` on every block mapped by `node`, with the logical block
+    /// for data and `None` for indirect (or extent tree) blocks, which are
+    /// only read on from if `visit` returns true.
+    pub(super) fn visit_mapped(
+        &self,
+        node: &inode_t,
+        visit: &mut dyn FnMut(Option<usize>, block_p) -> bool,
+    ) {
+        if node.is_fast_symlink() {
+            return;
+        }
+        if node.flags & INODE_EXTENTS != 0 {
+            return self.ext_visit(node, visit);
+        }
+        for s in 0..=TRI_SLOT {
+            let b = slot(node, s);
+            let (first, depth, _) = self.slot_range(s);
+            if b == 0 {
+                continue;
+            }
+            if depth == 0 {
+                visit(Some(first), b);
+            } else if visit(None, b) {
+                self.visit_indirect(b, depth, first, visit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{testing::Image, FEATURE_EXTENTS};
+
+    #[test]
+    fn triple_indirect_round_trip() {
+        let img = Image::format("bmap-triple", 1024, 2000, 256);
+        let p = 256;
+        let first = 12 + p + p * p;
+        let last = first + p * p * p - 1;
+        let blocks = [(first, 1), (first + p * p + p + 1, 2), (last, 3)];
+        img.mount(|fs| {
+            // block pointers, as on images from before extents
+            fs.sb.feature_incompat &= !FEATURE_EXTENTS;
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            let id = fs.lookup(c"/f").unwrap();
+            for (n, byte) in blocks {
+                assert_eq!(fs.write_at(id, &[byte; 1024], n * 1024).unwrap(), 1024);
+            }
+            let node = fs.get_inode_by_id(id);
+            assert_eq!(node.flags & INODE_EXTENTS, 0);
+            assert_ne!(node.tri_inblock, 0);
+            assert_eq!(node.size, (last as u64 + 1) * 1024);
+            // the triple indirect block, and a double and a single one for each
+            assert_eq!(fs.count_mapped(&node), (3, 7));
+            fs.unmount().unwrap();
+        });
+        img.mount(|fs| {
+            let id = fs.lookup(c"/f").unwrap();
+            let node = fs.get_inode_by_id(id);
+            let mut buf = [0; 1024];
+            for (n, byte) in blocks {
+                assert_ne!(fs.bmap(&node, n), 0);
+                assert_eq!(fs.read_at(id, n * 1024, &mut buf), 1024);
+                assert!(buf.iter().all(|b| *b == byte));
+            }
+            assert_eq!(fs.bmap(&node, first + 1), 0);
+            assert!(fs.check().is_empty());
+
+            let free = fs.blocks_bitmap.free;
+            fs.truncate(c"/f", 0).unwrap();
+            assert_eq!(fs.get_inode_by_id(id).tri_inblock, 0);
+            assert_eq!(fs.blocks_bitmap.free, free + 10);
+        });
+    }
+}
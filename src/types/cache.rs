@@ -0,0 +1,105 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+use super::{cache_stats_t, inode_p, inode_t, FileSystem};
+
+/// Entries a cache holds before it is emptied and starts over.
+const DENTRY_CACHE_MAX: usize = 65536;
+const INODE_CACHE_MAX: usize = 16384;
+
+/// Names resolved in each directory, `None` for names known not to exist,
+/// and inodes by number. Lookups only borrow the filesystem, so both fill up
+/// through interior mutability; every change to a directory or inode has to
+/// go through the methods below to keep them right.
+#[derive(Debug, Default)]
+pub struct Cache {
+    dentries: RefCell<HashMap<(inode_p, String), Option<inode_p>>>,
+    inodes: RefCell<HashMap<inode_p, inode_t>>,
+    stats: Cell<cache_stats_t>,
+}
+
+impl Cache {
+    fn count(&self, f: impl FnOnce(&mut cache_stats_t)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+}
+
+impl<'a> FileSystem<'a> {
+    /// Inode number of `name` in directory `dir_id`, reading the directory
+    /// only when the answer isn't cached yet.
+    pub(super) fn lookup_child(
+        &self,
+        dir_id: inode_p,
+        dir: &inode_t,
+        name: &str,
+    ) -> Option<inode_p> {
+        let key = (dir_id, name.to_string());
+        if let Some(id) = self.cache.dentries.borrow().get(&key) {
+            self.cache.count(|s| match id {
+                Some(_) => s.dentry_hits += 1,
+                None => s.dentry_negative_hits += 1,
+            });
+            return *id;
+        }
+        self.cache.count(|s| s.dentry_misses += 1);
+        let id = self.search_directory_get_id(dir, name);
+        // the name may be in a block that failed its checksum
+        if id.is_some() || !self.io_error.get() {
+            self.cache_dentry(dir_id, name, id);
+        }
+        id
+    }
+
+    pub(super) fn cache_dentry(&self, dir_id: inode_p, name: &str, id: Option<inode_p>) {
+        let mut dentries = self.cache.dentries.borrow_mut();
+        if dentries.len() >= DENTRY_CACHE_MAX {
+            dentries.clear();
+        }
+        dentries.insert((dir_id, name.to_string()), id);
+    }
+
+    pub(super) fn forget_dentry(&self, dir_id: inode_p, name: &str) {
+        self.cache
+            .dentries
+            .borrow_mut()
+            .remove(&(dir_id, name.to_string()));
+    }
+
+    /// Drops every name cached for a directory that is being deleted, before
+    /// its inode number gets reused.
+    pub(super) fn forget_dir(&self, dir_id: inode_p) {
+        self.cache
+            .dentries
+            .borrow_mut()
+            .retain(|(parent, _), _| *parent != dir_id);
+    }
+
+    pub(super) fn cached_inode(&self, id: inode_p) -> Option<inode_t> {
+        let node = self.cache.inodes.borrow().get(&id).copied();
+        self.cache.count(|s| match node {
+            Some(_) => s.inode_hits += 1,
+            None => s.inode_misses += 1,
+        });
+        node
+    }
+
+    pub(super) fn cache_inode(&self, id: inode_p, node: inode_t) {
+        let mut inodes = self.cache.inodes.borrow_mut();
+        if inodes.len() >= INODE_CACHE_MAX {
+            inodes.clear();
+        }
+        inodes.insert(id, node);
+    }
+
+    pub(super) fn forget_inodes(&self) {
+        self.cache.inodes.borrow_mut().clear();
+    }
+
+    pub fn cache_stats(&self) -> cache_stats_t {
+        self.cache.stats.get()
+    }
+}
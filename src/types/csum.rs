@@ -0,0 +1,200 @@
+use super::{block_p, inode_p, inode_t, FileSystem, FEATURE_METADATA_CSUM, STATE_ERRORS};
+
+/// Bytes at the end of every directory and index block kept for its
+/// checksum: a zero word, which ends the walk over the entries, and the CRC.
+const DIR_TAIL: usize = 8;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// CRC-32C (Castagnoli) of `data`, carrying on from `crc`, which is 0 for a
+/// fresh start.
+pub(super) fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, b| {
+        CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl<'a> FileSystem<'a> {
+    pub(super) fn csum_enabled(&self) -> bool {
+        self.sb.feature_ro_compat & FEATURE_METADATA_CSUM != 0
+    }
+
+    /// Every checksum starts from the UUID, so blocks copied over from
+    /// another image don't pass.
+    fn csum_seed(&self) -> u32 {
+        crc32c(0, &self.sb.uuid)
+    }
+
+    /// Covers the inode number too, an inode written to the wrong slot fails.
+    pub(super) fn inode_csum(&self, id: inode_p, node: &inode_t) -> u32 {
+        let data: [u8; 128] = zerocopy::transmute!(*node);
+        let crc = crc32c(self.csum_seed(), &id.to_le_bytes());
+        crc32c(crc, &data[..124])
+    }
+
+    /// Room for entries in a directory block, everything but the tail.
+    pub(super) fn dir_block_room(&self) -> usize {
+        let bs = self.sb.block_size as usize;
+        if self.csum_enabled() {
+            bs - DIR_TAIL
+        } else {
+            bs
+        }
+    }
+
+    fn dir_block_csum(&self, b: block_p, block: &[u8]) -> u32 {
+        let crc = crc32c(self.csum_seed(), &b.to_le_bytes());
+        crc32c(crc, &block[..block.len() - 4])
+    }
+
+    /// Writes the tail of directory block `b` after its entries changed.
+    pub(super) fn seal_dir_block(&mut self, b: block_p) {
+        if !self.csum_enabled() || b == 0 {
+            return;
+        }
+        let csum = self.dir_block_csum(b, self.get_data_block(b));
+        let block = self.get_data_block_mut(b);
+        let len = block.len();
+        block[len - DIR_TAIL..len - 4].fill(0);
+        block[len - 4..].copy_from_slice(&csum.to_le_bytes());
+    }
+
+    /// Seals the blocks of directory `id` that `offset..offset + len` touched.
+    pub(super) fn seal_dir_range(&mut self, id: inode_p, offset: usize, len: usize) {
+        let bs = self.sb.block_size as usize;
+        let node = self.get_inode_by_id(id);
+        for n in offset / bs..(offset + len).div_ceil(bs) {
+            let b = self.bmap(&node, n);
+            self.seal_dir_block(b);
+        }
+    }
+
+    /// Directory block `b` if its checksum holds, else `None` with the
+    /// failure recorded.
+    pub(super) fn dir_block(&self, b: block_p) -> Option<&[u8]> {
+        let block = self.get_data_block(b);
+        if self.csum_enabled() {
+            let stored = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap());
+            if stored != self.dir_block_csum(b, block) {
+                self.checksum_failed(&format!("directory block {b}"));
+                return None;
+            }
+        }
+        Some(block)
+    }
+
+    pub(super) fn checksum_failed(&self, what: &str) {
+        eprintln!("checksum mismatch in {what}");
+        self.io_error.set(true);
+    }
+
+    /// Whether a checksum failed since the last call. The image is then
+    /// marked as having errors for fsck to look at.
+    pub fn take_io_error(&mut self) -> bool {
+        if !self.io_error.replace(false) {
+            return false;
+        }
+        if self.sb.state & STATE_ERRORS == 0 {
+            self.sb.state |= STATE_ERRORS;
+            self.save();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{testing::Image, Error};
+
+    /// Image with "/d/x", and where on it the first block of "/d" is.
+    fn with_dir(name: &str) -> (Image, u64) {
+        let img = Image::format(name, 1024, 2000, 256);
+        let offset = img.mount(|fs| {
+            fs.create_directory(c"/d", 0, 0).unwrap();
+            fs.create_file(c"/d/x", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            fs.unmount().unwrap();
+            let (d, _) = fs.find_file_mut("/d").unwrap();
+            let bs = fs.sb.block_size as u64;
+            (FileSystem::layout(&fs.sb).2 + fs.bmap(&d, 0) as u64) * bs
+        });
+        (img, offset)
+    }
+
+    fn flip(img: &Image, offset: u64) {
+        let mut byte = img.read(offset, 1);
+        byte[0] ^= 1;
+        img.write(offset, &byte);
+    }
+
+    #[test]
+    fn bad_inode_is_an_io_error() {
+        let (img, _) = with_dir("csum-inode");
+        let offset = img.mount(|fs| {
+            let (_, id) = fs.find_file_mut("/d/x").unwrap();
+            let bs = fs.sb.block_size as u64;
+            FileSystem::layout(&fs.sb).0 * bs + 128 * id as u64
+        });
+        // in the middle of the size
+        flip(&img, offset + 10);
+        img.mount(|fs| {
+            assert!(fs.get_attr(c"/d/x").is_none());
+            assert!(fs.take_io_error());
+            assert!(matches!(fs.unlink_file("/d/x"), Err(Error::Io(_))));
+            assert!(fs.take_io_error());
+            // the rest of the image is fine
+            assert!(fs.get_attr(c"/d").is_some());
+            assert!(!fs.take_io_error());
+            assert_ne!(fs.sb.state & STATE_ERRORS, 0);
+        });
+    }
+
+    #[test]
+    fn bad_directory_block_is_an_io_error() {
+        let (img, offset) = with_dir("csum-dir");
+        flip(&img, offset + 12);
+        img.mount(|fs| {
+            assert!(fs.get_files_in_dir(c"/d").is_empty());
+            assert!(fs.take_io_error());
+            assert!(matches!(
+                fs.create_file(c"/d/y", &[], 0x8000 | 0o644, 0, 0),
+                Err(Error::Io(_))
+            ));
+            assert!(fs.take_io_error());
+            assert!(matches!(fs.unlink_dir(c"/d"), Err(Error::Io(_))));
+            assert!(fs.take_io_error());
+        });
+    }
+
+    #[test]
+    fn miss_in_a_bad_directory_block_is_not_cached() {
+        let (img, offset) = with_dir("csum-nocache");
+        flip(&img, offset + 12);
+        img.mount(|fs| {
+            for _ in 0..2 {
+                assert_eq!(fs.lookup(c"/d/x"), None);
+                assert!(fs.take_io_error());
+            }
+        });
+    }
+}
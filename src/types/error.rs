@@ -0,0 +1,85 @@
+use std::fmt;
+
+/// Why a filesystem operation failed. The bindings hand it to C as a negated
+/// errno, the variants that carry a description also get printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    Exists,
+    NotDirectory,
+    NotEmpty,
+    NameTooLong,
+    NoSpace,
+    TooBig,
+    NotPermitted,
+    Access,
+    NoAttribute,
+    /// A file handle for an inode that has gone since.
+    StaleHandle,
+    /// An owner or group id past the 16 bits the image keeps.
+    Overflow,
+    Invalid(&'static str),
+    Unsupported(&'static str),
+    /// A checksum that failed, or the image that couldn't be written.
+    Io(&'static str),
+}
+
+// Linux numbering, which is what FUSE runs on
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EINVAL: i32 = 22;
+const EFBIG: i32 = 27;
+const ENOSPC: i32 = 28;
+const ENAMETOOLONG: i32 = 36;
+const ENOTEMPTY: i32 = 39;
+const ENODATA: i32 = 61;
+const EOVERFLOW: i32 = 75;
+const EOPNOTSUPP: i32 = 95;
+
+impl Error {
+    pub fn errno(self) -> i32 {
+        match self {
+            Error::NotFound => ENOENT,
+            Error::Exists => EEXIST,
+            Error::NotDirectory => ENOTDIR,
+            Error::NotEmpty => ENOTEMPTY,
+            Error::NameTooLong => ENAMETOOLONG,
+            Error::NoSpace => ENOSPC,
+            Error::TooBig => EFBIG,
+            Error::NotPermitted => EPERM,
+            Error::Access => EACCES,
+            Error::NoAttribute => ENODATA,
+            Error::StaleHandle => EBADF,
+            Error::Overflow => EOVERFLOW,
+            Error::Invalid(_) => EINVAL,
+            Error::Unsupported(_) => EOPNOTSUPP,
+            Error::Io(_) => EIO,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Error::NotFound => "file not found",
+            Error::Exists => "file already exists",
+            Error::NotDirectory => "not a directory",
+            Error::NotEmpty => "directory not empty",
+            Error::NameTooLong => "name too long",
+            Error::NoSpace => "out of space",
+            Error::TooBig => "file too big",
+            Error::NotPermitted => "operation not permitted",
+            Error::Access => "permission denied",
+            Error::NoAttribute => "no such attribute",
+            Error::StaleHandle => "stale file handle",
+            Error::Overflow => "id doesn't fit in 16 bits",
+            Error::Invalid(msg) | Error::Unsupported(msg) | Error::Io(msg) => msg,
+        };
+        f.write_str(msg)
+    }
+}
@@ -0,0 +1,468 @@
+use super::{block_p, inode_p, inode_t, Error, FileSystem, INODE_EXTENTS};
+
+/// Extent nodes start with a header: magic, number of entries, room for
+/// entries and levels of index below the node. The root lives in the block
+/// pointer area of the inode, every other node takes a whole block.
+const EXT_MAGIC: u16 = 0xF30A;
+const EXT_HEADER: usize = 12;
+const EXT_ENTRY: usize = 12;
+/// Entries that fit next to the header in the 60 bytes of block pointers.
+const EXT_ROOT_MAX: usize = 4;
+/// Longest run preallocated in one go.
+const EXT_RUN_MAX: usize = 32768;
+
+/// At depth 0 a run of `len` blocks mapping logical block `logical` onward
+/// to `start` onward: `(logical, len, start)`. Above it the first logical
+/// block below a child node and its block: `(logical, child, 0)`.
+type Extent = (u32, u32, u32);
+
+#[derive(Debug, Clone, Copy)]
+enum Loc {
+    Root,
+    Block(block_p),
+}
+
+fn parse_node(data: &[u8]) -> (u16, Vec<Extent>) {
+    let half = |i: usize| u16::from_le_bytes(data[i..i + 2].try_into().unwrap());
+    let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    if half(0) != EXT_MAGIC {
+        return (0, vec![]);
+    }
+    let entries = (0..half(2) as usize)
+        .map(|i| {
+            let at = EXT_HEADER + i * EXT_ENTRY;
+            (word(at), word(at + 4), word(at + 8))
+        })
+        .collect();
+    (half(6), entries)
+}
+
+fn write_node(data: &mut [u8], depth: u16, entries: &[Extent]) {
+    let max = (data.len() - EXT_HEADER) / EXT_ENTRY;
+    data.fill(0);
+    data[0..2].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+    data[2..4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    data[4..6].copy_from_slice(&(max as u16).to_le_bytes());
+    data[6..8].copy_from_slice(&depth.to_le_bytes());
+    for (i, (a, b, c)) in entries.iter().enumerate() {
+        let at = EXT_HEADER + i * EXT_ENTRY;
+        data[at..at + 4].copy_from_slice(&a.to_le_bytes());
+        data[at + 4..at + 8].copy_from_slice(&b.to_le_bytes());
+        data[at + 8..at + 12].copy_from_slice(&c.to_le_bytes());
+    }
+}
+
+/// Entry of `entries` covering logical block `n`: the last one starting at
+/// or before it, or the first one when `n` comes before all of them.
+fn find_pos(entries: &[Extent], n: u32) -> usize {
+    entries.partition_point(|e| e.0 <= n).saturating_sub(1)
+}
+
+/// Switches an inode with no blocks to extents.
+pub(super) fn init_extents(node: &mut inode_t) {
+    let mut root = [0u8; EXT_HEADER + EXT_ROOT_MAX * EXT_ENTRY];
+    write_node(&mut root, 0, &[]);
+    node.set_inline_data(&root);
+    node.flags |= INODE_EXTENTS;
+}
+
+impl<'a> FileSystem<'a> {
+    fn ext_read(&self, node: &inode_t, loc: Loc) -> (u16, Vec<Extent>) {
+        match loc {
+            Loc::Root => parse_node(&node.inline_data()),
+            Loc::Block(b) => parse_node(self.get_data_block(b)),
+        }
+    }
+
+    fn ext_write(&mut self, node: &mut inode_t, loc: Loc, depth: u16, entries: &[Extent]) {
+        match loc {
+            Loc::Root => {
+                let mut root = node.inline_data();
+                write_node(&mut root, depth, entries);
+                node.set_inline_data(&root);
+            }
+            Loc::Block(b) => write_node(self.get_data_block_mut(b), depth, entries),
+        }
+    }
+
+    fn ext_capacity(&self, loc: Loc) -> usize {
+        match loc {
+            Loc::Root => EXT_ROOT_MAX,
+            Loc::Block(_) => (self.sb.block_size as usize - EXT_HEADER) / EXT_ENTRY,
+        }
+    }
+
+    /// Nodes from the root down to the leaf covering logical block `n`, with
+    /// the position of the entry followed in each.
+    fn ext_path(&self, node: &inode_t, n: u32) -> Vec<(Loc, usize)> {
+        let mut path = vec![];
+        let mut loc = Loc::Root;
+        loop {
+            let (depth, entries) = self.ext_read(node, loc);
+            let pos = find_pos(&entries, n);
+            path.push((loc, pos));
+            if depth == 0 || entries.is_empty() {
+                return path;
+            }
+            loc = Loc::Block(entries[pos].1);
+        }
+    }
+
+    /// Physical block of logical block `n` and how many blocks from there on
+    /// are contiguous; a hole gives block 0 and the distance to whatever is
+    /// mapped next.
+    pub(super) fn ext_map(&self, node: &inode_t, n: usize) -> (block_p, usize) {
+        let Ok(n) = u32::try_from(n) else {
+            return (0, 1);
+        };
+        let path = self.ext_path(node, n);
+        // the next entry up the tree bounds a hole past the end of the leaf
+        let mut next = u32::MAX as u64 + 1;
+        for (loc, pos) in &path[..path.len() - 1] {
+            if let Some(e) = self.ext_read(node, *loc).1.get(pos + 1) {
+                next = next.min(e.0 as u64);
+            }
+        }
+        let (loc, _) = *path.last().unwrap();
+        let (_, entries) = self.ext_read(node, loc);
+        for (logical, len, start) in entries {
+            if n < logical {
+                return (0, (logical - n) as usize);
+            }
+            if n - logical < len {
+                return (start + n - logical, (len - (n - logical)) as usize);
+            }
+        }
+        (0, (next - n as u64) as usize)
+    }
+
+    /// Maps logical block `n` of inode `id` to a new block placed where the
+    /// nearest extent would put it, growing that extent when it ends right
+    /// before `n`, so files written in order stay in a single run.
+    pub(super) fn ext_alloc(&mut self, id: inode_p, n: usize) -> Result<block_p, Error> {
+        let n = u32::try_from(n).map_err(|_| Error::TooBig)?;
+        let node = self.get_inode_by_id(id);
+        let path = self.ext_path(&node, n);
+        let (loc, pos) = *path.last().unwrap();
+        let entries = self.ext_read(&node, loc).1;
+        let goal = match entries.get(pos) {
+            Some(e) if e.0 <= n && n - e.0 < e.1 => return Ok(e.2 + n - e.0),
+            Some(e) if e.0 <= n => (e.2 + (n - e.0)) as usize,
+            Some(e) => e.2.saturating_sub(e.0 - n) as usize,
+            None => 0,
+        };
+        let b = self.alloc_zeroed_block_near(goal)?;
+        self.ext_map_run(id, n, 1, b)?;
+        Ok(b)
+    }
+
+    /// Maps up to `count` logical blocks from `n` on, which are all holes,
+    /// to a single run of zeroed blocks if one is free. Returns how many
+    /// blocks got mapped, at least one.
+    pub(super) fn ext_alloc_run(
+        &mut self,
+        id: inode_p,
+        n: usize,
+        count: usize,
+    ) -> Result<usize, Error> {
+        let count = count
+            .min(EXT_RUN_MAX)
+            .min((u32::MAX as usize).saturating_sub(n));
+        if count == 0 {
+            return Err(Error::TooBig);
+        }
+        let Some(start) = self.blocks_bitmap.allocate_contiguous(count) else {
+            return self.ext_alloc(id, n).map(|_| 1);
+        };
+        for b in start..start + count {
+            self.get_file_block_mut(b as block_p).fill(0);
+        }
+        self.ext_map_run(id, n as u32, count as u32, start as block_p)?;
+        Ok(count)
+    }
+
+    /// Records that logical blocks `n..n + len` now live at `start` onward,
+    /// growing the extent before them if the run carries it on. The blocks
+    /// are released again if the tree has no room for a new extent.
+    fn ext_map_run(&mut self, id: inode_p, n: u32, len: u32, start: block_p) -> Result<(), Error> {
+        let mut node = self.get_inode_by_id(id);
+        let path = self.ext_path(&node, n);
+        let (loc, pos) = *path.last().unwrap();
+        let (depth, mut entries) = self.ext_read(&node, loc);
+        if let Some(e) = entries.get_mut(pos) {
+            if e.0 as u64 + e.1 as u64 == n as u64
+                && e.2 as u64 + e.1 as u64 == start as u64
+                && e.1 as u64 + len as u64 <= u32::MAX as u64
+            {
+                e.1 += len;
+                self.ext_write(&mut node, loc, depth, &entries);
+                self.save_inode(id, node);
+                return Ok(());
+            }
+        }
+        if let Err(e) = self.ext_insert(&mut node, &path, (n, len, start)) {
+            for b in start..start + len {
+                self.blocks_bitmap.free(b as usize);
+            }
+            return Err(e);
+        }
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    /// Adds `extent` to the leaf `path` ends at, splitting the nodes that
+    /// overflow. A full root moves its entries into a new block one level down.
+    fn ext_insert(
+        &mut self,
+        node: &mut inode_t,
+        path: &[(Loc, usize)],
+        extent: Extent,
+    ) -> Result<(), Error> {
+        let full = path
+            .iter()
+            .rev()
+            .take_while(|(loc, _)| self.ext_read(node, *loc).1.len() >= self.ext_capacity(*loc))
+            .count();
+        // take every block up front so a full image can't leave half a split
+        let mut spare = vec![];
+        for _ in 0..full {
+            match self.alloc_zeroed_block() {
+                Ok(b) => spare.push(b),
+                Err(e) => {
+                    for b in spare {
+                        self.blocks_bitmap.free(b as usize);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut entry = extent;
+        for (level, (loc, pos)) in path.iter().enumerate().rev() {
+            let (depth, mut entries) = self.ext_read(node, *loc);
+            if depth == 0 {
+                let at = entries.partition_point(|e| e.0 < entry.0);
+                entries.insert(at, entry);
+            } else {
+                entries.insert(pos + 1, entry);
+            }
+            if entries.len() <= self.ext_capacity(*loc) {
+                self.ext_write(node, *loc, depth, &entries);
+                return Ok(());
+            }
+            let new = spare.pop().unwrap();
+            if level == 0 {
+                self.ext_write(node, Loc::Block(new), depth, &entries);
+                self.ext_write(node, Loc::Root, depth + 1, &[(0, new, 0)]);
+                return Ok(());
+            }
+            let right = entries.split_off(entries.len() / 2);
+            self.ext_write(node, *loc, depth, &entries);
+            self.ext_write(node, Loc::Block(new), depth, &right);
+            entry = (right[0].0, new, 0);
+        }
+        unreachable!("the root always takes the last entry");
+    }
+
+    /// Every extent below `loc` in order, and the blocks of the nodes holding them.
+    fn ext_collect(
+        &self,
+        node: &inode_t,
+        loc: Loc,
+        extents: &mut Vec<Extent>,
+        nodes: &mut Vec<block_p>,
+    ) {
+        let (depth, entries) = self.ext_read(node, loc);
+        if let Loc::Block(b) = loc {
+            nodes.push(b);
+        }
+        if depth == 0 {
+            extents.extend(entries);
+            return;
+        }
+        for (_, child, _) in entries {
+            self.ext_collect(node, Loc::Block(child), extents, nodes);
+        }
+    }
+
+    /// Lays `extents` out in a fresh tree, packing the nodes full.
+    fn ext_build(&mut self, node: &mut inode_t, extents: Vec<Extent>) -> Result<(), Error> {
+        let cap = self.ext_capacity(Loc::Block(0));
+        let mut level = extents;
+        let mut depth = 0;
+        while level.len() > EXT_ROOT_MAX {
+            level = level
+                .chunks(cap)
+                .map(|chunk| {
+                    let b = self.alloc_zeroed_block()?;
+                    self.ext_write(node, Loc::Block(b), depth, chunk);
+                    Ok((chunk[0].0, b, 0))
+                })
+                .collect::<Result<_, Error>>()?;
+            depth += 1;
+        }
+        self.ext_write(node, Loc::Root, depth, &level);
+        Ok(())
+    }
+
+    /// Turns logical blocks `first..last` of inode `id` into holes. The tree
+    /// is rebuilt from the extents that are left.
+    pub(super) fn ext_free_range(
+        &mut self,
+        id: inode_p,
+        first: usize,
+        last: usize,
+    ) -> Result<(), Error> {
+        let mut node = self.get_inode_by_id(id);
+        let mut extents = vec![];
+        let mut nodes = vec![];
+        self.ext_collect(&node, Loc::Root, &mut extents, &mut nodes);
+        let mut kept = Vec::with_capacity(extents.len() + 1);
+        let mut changed = false;
+        for (logical, len, start) in extents {
+            let (a, b) = (logical as usize, logical as usize + len as usize);
+            if b <= first || a >= last {
+                kept.push((logical, len, start));
+                continue;
+            }
+            changed = true;
+            let (from, to) = (a.max(first), b.min(last));
+            for n in from..to {
+                self.blocks_bitmap.free(start as usize + n - a);
+            }
+            if a < from {
+                kept.push((logical, (from - a) as u32, start));
+            }
+            if to < b {
+                kept.push((to as u32, (b - to) as u32, start + (to - a) as u32));
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        for b in nodes {
+            self.blocks_bitmap.free(b as usize);
+        }
+        // the nodes just freed and the data block a split extent gave back
+        // should always do
+        self.ext_build(&mut node, kept)?;
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    /// Number of data blocks and of extent tree blocks of `node`.
+    pub(super) fn ext_count(&self, node: &inode_t) -> (usize, usize) {
+        let mut extents = vec![];
+        let mut nodes = vec![];
+        self.ext_collect(node, Loc::Root, &mut extents, &mut nodes);
+        let data = extents.iter().map(|e| e.1 as usize).sum();
+        (data, nodes.len())
+    }
+
+    fn ext_visit_node(
+        &self,
+        node: &inode_t,
+        loc: Loc,
+        visit: &mut dyn FnMut(Option<usize>, block_p) -> bool,
+    ) {
+        let (depth, entries) = self.ext_read(node, loc);
+        for (logical, len, start) in entries {
+            if depth != 0 {
+                if visit(None, len) {
+                    self.ext_visit_node(node, Loc::Block(len), visit);
+                }
+                continue;
+            }
+            for i in 0..len {
+                if !visit(Some(logical as usize + i as usize), start + i) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// `visit_mapped` for an extent tree; a run stops at the first block
+    /// `visit` turns down.
+    pub(super) fn ext_visit(
+        &self,
+        node: &inode_t,
+        visit: &mut dyn FnMut(Option<usize>, block_p) -> bool,
+    ) {
+        self.ext_visit_node(node, Loc::Root, visit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::testing::Image;
+
+    fn file(fs: &FileSystem) -> (inode_t, inode_p) {
+        fs.find_file_mut("/f").unwrap()
+    }
+
+    #[test]
+    fn contiguous_write_is_one_extent() {
+        let img = Image::format("extent-run", 1024, 4000, 256);
+        img.mount(|fs| {
+            let data: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+            fs.create_file(c"/f", &data, 0x8000 | 0o644, 0, 0).unwrap();
+            let (node, id) = file(fs);
+            assert_ne!(node.flags & INODE_EXTENTS, 0);
+            assert_eq!(
+                fs.ext_read(&node, Loc::Root),
+                (0, vec![(0, 200, fs.bmap(&node, 0))])
+            );
+            assert_eq!(fs.ext_map(&node, 10).1, 190);
+
+            // a hole in the middle splits it
+            fs.fallocate(c"/f", 3, 50 * 1024, 10 * 1024).unwrap();
+            let (node, _) = file(fs);
+            assert_eq!(fs.ext_read(&node, Loc::Root).1.len(), 2);
+            assert_eq!(fs.ext_map(&node, 50), (0, 10));
+            let mut buf = vec![1; 1024];
+            assert_eq!(fs.read_at(id, 55 * 1024, &mut buf), 1024);
+            assert!(buf.iter().all(|b| *b == 0));
+            fs.read_at(id, 60 * 1024, &mut buf);
+            assert_eq!(buf[..], data[60 * 1024..61 * 1024]);
+            assert!(fs.check().is_empty());
+        });
+    }
+
+    #[test]
+    fn fragmented_file_grows_a_tree() {
+        let img = Image::format("extent-tree", 1024, 4000, 256);
+        img.mount(|fs| {
+            let free = fs.blocks_bitmap.free;
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            let (_, id) = file(fs);
+            for i in 0..300 {
+                fs.write_at(id, &[(i % 250) as u8 + 1; 1024], i * 2 * 1024)
+                    .unwrap();
+            }
+            let (node, _) = file(fs);
+            let (depth, _) = fs.ext_read(&node, Loc::Root);
+            assert!(depth > 0);
+            let (data, nodes) = fs.ext_count(&node);
+            assert_eq!(data, 300);
+            assert!(nodes > 0);
+            let mut buf = [0; 1024];
+            for i in 0..300 {
+                fs.read_at(id, i * 2 * 1024, &mut buf);
+                assert!(buf.iter().all(|b| *b == (i % 250) as u8 + 1));
+                if i < 299 {
+                    buf.fill(0xff);
+                    assert_eq!(fs.read_at(id, (i * 2 + 1) * 1024, &mut buf), 1024);
+                    assert!(buf.iter().all(|b| *b == 0));
+                }
+            }
+            assert!(fs.check().is_empty());
+
+            fs.truncate(c"/f", 0).unwrap();
+            let (node, _) = file(fs);
+            assert_eq!(fs.ext_count(&node), (0, 0));
+            assert_eq!(fs.blocks_bitmap.free, free);
+            assert!(fs.check().is_empty());
+        });
+    }
+}
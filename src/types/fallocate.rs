@@ -0,0 +1,215 @@
+use std::ffi::CStr;
+
+use zerocopy::FromZeros;
+
+use super::{inode_p, Error, FileSystem, INODE_PREALLOC};
+
+/// `fallocate` mode bits, with the values Linux uses for them.
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
+impl<'a> FileSystem<'a> {
+    /// Zeroes `start..end` of inode `id`, freeing the blocks it fully covers
+    /// and clearing the partial ones at its edges.
+    fn punch_hole(&mut self, id: inode_p, start: usize, end: usize) -> Result<(), Error> {
+        let bs = self.sb.block_size as usize;
+        let first = start.div_ceil(bs);
+        let last = end / bs;
+        // first, it is the part that can fail
+        if first < last {
+            self.free_range(id, first, last)?;
+        }
+        let node = self.get_inode_by_id(id);
+        if !start.is_multiple_of(bs) {
+            let b = self.bmap(&node, start / bs);
+            if b != 0 {
+                let block_end = end.min((start / bs + 1) * bs) - start / bs * bs;
+                self.get_file_block_mut(b)[start % bs..block_end].zero();
+            }
+        }
+        if !end.is_multiple_of(bs) && last >= first {
+            let b = self.bmap(&node, last);
+            if b != 0 {
+                self.get_file_block_mut(b)[..end % bs].zero();
+            }
+        }
+        Ok(())
+    }
+
+    /// Preallocates, zeroes or deallocates `len` bytes at `offset` depending
+    /// on `mode`, like fallocate(2).
+    pub fn fallocate(
+        &mut self,
+        path: &CStr,
+        mode: u32,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), Error> {
+        let supported = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE;
+        if mode & !supported != 0
+            || (mode & FALLOC_FL_PUNCH_HOLE != 0
+                && mode != FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)
+        {
+            return Err(Error::Unsupported("unsupported mode"));
+        }
+        if len == 0 {
+            return Err(Error::Invalid("invalid range"));
+        }
+        let (node, id) = self
+            .find_file_mut(path.to_str().expect("path should be UTF-8"))
+            .ok_or(Error::NotFound)?;
+        if node.type_perm & 0xF000 != 0x8000 {
+            return Err(Error::Unsupported("not a regular file"));
+        }
+        let end = offset.saturating_add(len);
+        if end > self.max_file_size() {
+            return Err(Error::TooBig);
+        }
+
+        if mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+            self.punch_hole(id, offset, end)?;
+        }
+        if mode & FALLOC_FL_PUNCH_HOLE == 0 {
+            let bs = self.sb.block_size as usize;
+            let last = end.div_ceil(bs);
+            let mut allocated = vec![];
+            let mut n = offset / bs;
+            while n < last {
+                let (b, run) = self.bmap_run(&self.get_inode_by_id(id), n);
+                let run = run.min(last - n);
+                if b != 0 {
+                    n += run;
+                    continue;
+                }
+                match self.bmap_alloc_run(id, n, run) {
+                    Ok(len) => {
+                        allocated.push((n, len));
+                        n += len;
+                    }
+                    Err(e) => {
+                        // don't leave half of the preallocation behind
+                        allocated.push((n, 1));
+                        for (n, len) in allocated {
+                            // what can't be given back is left for fsck
+                            let _ = self.free_range(id, n, n + len);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut node = self.get_inode_by_id(id);
+        let bs = self.sb.block_size as u64;
+        if mode & FALLOC_FL_KEEP_SIZE == 0 && (node.size as usize) < end {
+            node.size = end as u64;
+        } else if mode & FALLOC_FL_PUNCH_HOLE == 0 && end as u64 > node.size.div_ceil(bs) * bs {
+            node.flags |= INODE_PREALLOC;
+        }
+        node.touch_modified();
+        self.save_inode(id, node);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::testing::Image;
+
+    const KB: usize = 1024;
+
+    /// Image with "/f" holding 10 blocks of 0xaa.
+    fn with_file(name: &str) -> Image {
+        let img = Image::format(name, 1024, 2000, 256);
+        img.mount(|fs| {
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            let id = fs.lookup(c"/f").unwrap();
+            fs.write_at(id, &[0xaa; 10 * KB], 0).unwrap();
+            fs.unmount().unwrap();
+        });
+        img
+    }
+
+    fn contents(fs: &mut FileSystem, path: &CStr) -> Vec<u8> {
+        let id = fs.lookup(path).unwrap();
+        let mut buf = vec![0xff; fs.get_inode_by_id(id).size as usize];
+        assert_eq!(fs.read_at(id, 0, &mut buf), buf.len());
+        buf
+    }
+
+    #[test]
+    fn keep_size_preallocates_past_the_end() {
+        let img = with_file("falloc-keep");
+        img.mount(|fs| {
+            let free = fs.blocks_bitmap.free;
+            fs.fallocate(c"/f", FALLOC_FL_KEEP_SIZE, 8 * KB, 8 * KB)
+                .unwrap();
+            let node = fs.get_attr(c"/f").unwrap();
+            assert_eq!(node.size, 10 * KB as u64);
+            assert_ne!(node.flags & INODE_PREALLOC, 0);
+            assert_eq!(fs.count_mapped(&node).0, 16);
+            assert_eq!(fs.blocks_bitmap.free, free - 6);
+            assert!(fs.check().is_empty());
+
+            // growing into it only moves the size
+            fs.fallocate(c"/f", 0, 0, 12 * KB).unwrap();
+            assert_eq!(fs.blocks_bitmap.free, free - 6);
+            let data = contents(fs, c"/f");
+            assert_eq!(data.len(), 12 * KB);
+            assert!(data[..10 * KB].iter().all(|b| *b == 0xaa));
+            assert!(data[10 * KB..].iter().all(|b| *b == 0));
+
+            // and truncating gives back what is past the size
+            fs.truncate(c"/f", 12 * KB).unwrap();
+            let node = fs.get_attr(c"/f").unwrap();
+            assert_eq!(node.flags & INODE_PREALLOC, 0);
+            assert_eq!(fs.count_mapped(&node).0, 12);
+            assert_eq!(fs.blocks_bitmap.free, free - 2);
+            assert!(fs.check().is_empty());
+        });
+    }
+
+    #[test]
+    fn punch_hole_frees_whole_blocks() {
+        let img = with_file("falloc-punch");
+        img.mount(|fs| {
+            let free = fs.blocks_bitmap.free;
+            assert_eq!(
+                fs.fallocate(c"/f", FALLOC_FL_PUNCH_HOLE, 0, KB),
+                Err(Error::Unsupported("unsupported mode"))
+            );
+            let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+            fs.fallocate(c"/f", mode, KB + 512, 4 * KB).unwrap();
+            let node = fs.get_attr(c"/f").unwrap();
+            assert_eq!(node.size, 10 * KB as u64);
+            // blocks 2 to 4, the edges are only zeroed
+            assert_eq!(fs.blocks_bitmap.free, free + 3);
+            assert_eq!(fs.count_mapped(&node).0, 7);
+            let data = contents(fs, c"/f");
+            assert!(data[..KB + 512].iter().all(|b| *b == 0xaa));
+            assert!(data[KB + 512..5 * KB + 512].iter().all(|b| *b == 0));
+            assert!(data[5 * KB + 512..].iter().all(|b| *b == 0xaa));
+            assert!(fs.check().is_empty());
+        });
+    }
+
+    #[test]
+    fn zero_range_keeps_the_blocks() {
+        let img = with_file("falloc-zero");
+        img.mount(|fs| {
+            let free = fs.blocks_bitmap.free;
+            fs.fallocate(c"/f", FALLOC_FL_ZERO_RANGE, 8 * KB + 512, 4 * KB)
+                .unwrap();
+            let node = fs.get_attr(c"/f").unwrap();
+            assert_eq!(node.size, 12 * KB as u64 + 512);
+            assert_eq!(fs.count_mapped(&node).0, 13);
+            assert_eq!(fs.blocks_bitmap.free, free - 3);
+            let data = contents(fs, c"/f");
+            assert!(data[..8 * KB + 512].iter().all(|b| *b == 0xaa));
+            assert!(data[8 * KB + 512..].iter().all(|b| *b == 0));
+            assert!(fs.check().is_empty());
+        });
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashSet;
+
+use super::{
+    block_p, inode_p, inode_t, Error, FileSystem, INODE_PREALLOC, STATE_CLEAN, STATE_ERRORS,
+};
+
+/// Owner recorded for the blocks of the journal, which belong to no inode.
+const JOURNAL_OWNER: inode_p = inode_p::MAX;
+
+/// What the walk over the tree found so far.
+struct Walk {
+    /// Inode found using each data block, 0 for none yet.
+    owner: Vec<inode_p>,
+    /// Directory entries found for each inode.
+    links: Vec<u32>,
+    /// Inodes reached from the root, and the directories among them.
+    seen: Vec<bool>,
+    dirs: HashSet<inode_p>,
+    problems: Vec<String>,
+}
+
+fn owner_name(id: inode_p) -> String {
+    if id == JOURNAL_OWNER {
+        String::from("the journal")
+    } else {
+        format!("inode {id}")
+    }
+}
+
+impl Walk {
+    /// Records that `id` uses block `b`. False if `b` can't be its block,
+    /// then nothing is read from it.
+    fn claim(&mut self, fs: &FileSystem, id: inode_p, b: block_p) -> bool {
+        let n = b as usize;
+        if n == 0 || n >= self.owner.len() {
+            self.problems.push(format!(
+                "{} points at block {b}, outside the data area",
+                owner_name(id)
+            ));
+            return false;
+        }
+        if self.owner[n] != 0 {
+            self.problems.push(format!(
+                "block {b} is used by both {} and {}",
+                owner_name(self.owner[n]),
+                owner_name(id)
+            ));
+            return false;
+        }
+        self.owner[n] = id;
+        if !fs.blocks_bitmap.is_set(n) {
+            self.problems
+                .push(format!("block {b} of {} is marked free", owner_name(id)));
+        }
+        true
+    }
+}
+
+impl<'a> FileSystem<'a> {
+    /// Loads inode `id`, claims its blocks and checks its size against them.
+    /// `None` if it fails its checksum.
+    fn check_inode(&self, walk: &mut Walk, id: inode_p) -> Option<inode_t> {
+        let node = self.get_inode_by_id(id);
+        if self.io_error.replace(false) {
+            walk.problems.push(format!("inode {id} fails its checksum"));
+            return None;
+        }
+        let (mut count, mut end) = (0, 0);
+        self.visit_mapped(&node, &mut |n, b| {
+            if let Some(n) = n {
+                count += 1;
+                end = end.max(n + 1);
+            }
+            walk.claim(self, id, b)
+        });
+        self.visit_index(&node, &mut |b| walk.claim(self, id, b));
+        if node.xattr_block != 0 {
+            walk.claim(self, id, node.xattr_block);
+        }
+        if self.io_error.replace(false) {
+            walk.problems
+                .push(format!("inode {id}: an index block fails its checksum"));
+        }
+
+        let bs = self.sb.block_size as u64;
+        let size = node.size;
+        match node.type_perm & 0xF000 {
+            0x4000 if count == 0 => {
+                walk.problems.push(format!("directory {id} has no blocks"));
+            }
+            0x4000 if end != count => {
+                walk.problems.push(format!("directory {id} has holes"));
+            }
+            // it isn't kept exact, but doesn't reach past the blocks
+            0x4000 if size > count as u64 * bs => walk.problems.push(format!(
+                "directory {id} has size {size} but only {count} blocks"
+            )),
+            0xA000
+                if !node.is_fast_symlink() && (count != end || end as u64 != size.div_ceil(bs)) =>
+            {
+                walk.problems
+                    .push(format!("symlink {id} has size {size} but {count} blocks"))
+            }
+            0x8000 if size > self.max_file_size() as u64 => walk.problems.push(format!(
+                "file {id} has size {size}, past the largest possible"
+            )),
+            // unless fallocate put them there
+            0x8000 if node.flags & INODE_PREALLOC == 0 && end as u64 > size.div_ceil(bs) => walk
+                .problems
+                .push(format!("file {id} has blocks past its size of {size}")),
+            0x1000 | 0x2000 | 0x6000 | 0xC000 if count != 0 => walk
+                .problems
+                .push(format!("inode {id} has {count} blocks but holds no data")),
+            _ => {}
+        }
+        Some(node)
+    }
+
+    /// Reads the entries of directory `id`, found in `parent`, checking
+    /// `.` and `..` and that every entry leads to an allocated inode. Returns
+    /// the subdirectories not seen before.
+    fn check_dir(&self, walk: &mut Walk, id: inode_p, parent: inode_p) -> Vec<inode_p> {
+        let node = self.get_inode_by_id(id);
+        let data = self.get_dir_data(&node);
+        if self.io_error.replace(false) {
+            walk.problems.push(format!(
+                "directory {id} has a block failing its checksum, its entries are lost"
+            ));
+        }
+        let inodes = self.sb.inodes_num as usize;
+        let mut subdirs = vec![];
+        let mut rest = &data[..];
+        let mut i = 0;
+        while let Some(d) = self.dentry(rest) {
+            rest = &rest[d.size..];
+            i += 1;
+            let expected = match i {
+                1 => Some((".", id)),
+                2 => Some(("..", parent)),
+                _ => None,
+            };
+            if let Some((name, target)) = expected {
+                if d.name != name || d.inode_num != target {
+                    walk.problems.push(format!(
+                        "directory {id}: entry {i} is \"{}\" for inode {}, not \"{name}\" for inode {target}",
+                        d.name, d.inode_num
+                    ));
+                }
+            }
+            let target = d.inode_num;
+            if target as usize >= inodes || !self.inode_bitmap.is_set(target as usize) {
+                walk.problems.push(format!(
+                    "directory {id}: \"{}\" points at free inode {target}",
+                    d.name
+                ));
+                continue;
+            }
+            walk.links[target as usize] += 1;
+            if i <= 2 || d.name == "." || d.name == ".." {
+                continue;
+            }
+            if walk.seen[target as usize] {
+                if walk.dirs.contains(&target) {
+                    walk.problems.push(format!(
+                        "directory {target} is also in directory {id} as \"{}\"",
+                        d.name
+                    ));
+                }
+                continue;
+            }
+            walk.seen[target as usize] = true;
+            if let Some(child) = self.check_inode(walk, target) {
+                if child.is_directory() {
+                    walk.dirs.insert(target);
+                    subdirs.push(target);
+                }
+            }
+        }
+        if self.io_error.replace(false) {
+            walk.problems.push(format!(
+                "directory {id} has a mangled entry, the ones after it are lost"
+            ));
+        }
+        if i < 2 {
+            walk.problems
+                .push(format!("directory {id} is missing \".\" or \"..\""));
+        }
+        subdirs
+    }
+
+    /// Walks the tree from the root and checks it against the bitmaps: every
+    /// entry leads to an allocated inode, every block in use is marked and
+    /// used once, link counts match the entries, directories start with `.`
+    /// and `..`, and sizes fit the blocks. Returns what is wrong, one line
+    /// each; nothing gets repaired.
+    pub fn check(&self) -> Vec<String> {
+        let inodes = self.sb.inodes_num as usize;
+        let mut walk = Walk {
+            owner: vec![0; self.blocks_bitmap.size],
+            links: vec![0; inodes],
+            seen: vec![false; inodes],
+            dirs: HashSet::new(),
+            problems: vec![],
+        };
+        for b in self.journal.area() {
+            walk.claim(self, JOURNAL_OWNER, b);
+        }
+
+        walk.seen[1] = true;
+        match self.check_inode(&mut walk, 1) {
+            Some(root) if root.is_directory() => {
+                walk.dirs.insert(1);
+                let mut pending = vec![(1, 1)];
+                while let Some((id, parent)) = pending.pop() {
+                    let subdirs = self.check_dir(&mut walk, id, parent);
+                    pending.extend(subdirs.into_iter().map(|sub| (sub, id)));
+                }
+            }
+            Some(_) => walk
+                .problems
+                .push(String::from("the root isn't a directory")),
+            None => {}
+        }
+
+        for id in 1..inodes {
+            if !walk.seen[id] {
+                if self.inode_bitmap.is_set(id) {
+                    walk.problems
+                        .push(format!("inode {id} is marked used but in no directory"));
+                }
+                continue;
+            }
+            let node = self.get_inode_by_id(id as inode_p);
+            if self.io_error.replace(false) {
+                continue;
+            }
+            if node.hard_links != walk.links[id] {
+                walk.problems.push(format!(
+                    "inode {id} has {} links but {} entries",
+                    node.hard_links, walk.links[id]
+                ));
+            }
+        }
+        for b in 1..self.blocks_bitmap.size {
+            if walk.owner[b] == 0 && self.blocks_bitmap.is_set(b) {
+                walk.problems
+                    .push(format!("block {b} is marked used but nothing uses it"));
+            }
+        }
+        walk.problems
+    }
+
+    /// Runs `check` and records the outcome in the superblock, where the
+    /// error flag is set if anything is wrong. Otherwise the image, with
+    /// its journal replayed, is as good as cleanly unmounted.
+    pub fn fsck(&mut self) -> Result<Vec<String>, Error> {
+        let problems = self.check();
+        if problems.is_empty() {
+            self.sb.state &= !STATE_ERRORS;
+            self.sb.state |= STATE_CLEAN;
+        } else {
+            self.sb.state |= STATE_ERRORS;
+        }
+        self.save();
+        self.commit()?;
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::testing::Image;
+
+    /// Image with "/d/e", "/f" and its second link "/d/g".
+    fn populated(name: &str) -> Image {
+        let img = Image::format(name, 1024, 2000, 256);
+        img.mount(|fs| {
+            fs.create_directory(c"/d", 0, 0).unwrap();
+            fs.create_directory(c"/d/e", 0, 0).unwrap();
+            fs.create_file(c"/f", b"hello", 0x8000 | 0o644, 0, 0)
+                .unwrap();
+            fs.link(c"/f", c"/d/g").unwrap();
+            fs.unmount().unwrap();
+        });
+        img
+    }
+
+    /// Damages the image through `f` and returns what `check` finds.
+    fn check_after(img: &Image, f: impl FnOnce(&mut FileSystem)) -> Vec<String> {
+        img.open(|fs| {
+            f(fs);
+            fs.commit().unwrap();
+        })
+        .unwrap();
+        img.open(|fs| fs.check()).unwrap()
+    }
+
+    fn id_of(fs: &FileSystem, path: &str) -> inode_p {
+        fs.find_file_mut(path).unwrap().1
+    }
+
+    #[test]
+    fn healthy_image_is_clean() {
+        let img = populated("fsck-clean");
+        // left dirty by a crash
+        img.mount(|fs| fs.create_file(c"/h", &[], 0x8000 | 0o644, 0, 0).unwrap());
+        assert_eq!(
+            img.open(|fs| fs.fsck().unwrap()).unwrap(),
+            Vec::<String>::new()
+        );
+        img.open(|fs| assert_eq!(fs.sb.state & (STATE_CLEAN | STATE_ERRORS), STATE_CLEAN))
+            .unwrap();
+    }
+
+    #[test]
+    fn mounted_image_is_locked() {
+        let img = populated("fsck-locked");
+        img.mount(|_| {
+            assert!(matches!(img.open(|fs| fs.mount()), Ok(Err(Error::Io(_)))));
+        });
+        // a crash lets go of it too
+        img.mount(|fs| fs.unmount().unwrap());
+    }
+
+    #[test]
+    fn block_claimed_twice() {
+        let img = populated("fsck-twice");
+        let problems = check_after(&img, |fs| {
+            let d = fs.get_inode_by_id(id_of(fs, "/d"));
+            let id = id_of(fs, "/d/e");
+            let mut e = fs.get_inode_by_id(id);
+            e.direct_blocks[0] = d.direct_blocks[0];
+            fs.save_inode(id, e);
+        });
+        assert!(problems.iter().any(|p| p.contains("is used by both")));
+        // the block it had before leaks
+        assert!(problems.iter().any(|p| p.contains("nothing uses it")));
+        assert!(!img.open(|fs| fs.fsck().unwrap()).unwrap().is_empty());
+        img.open(|fs| assert_ne!(fs.sb.state & STATE_ERRORS, 0))
+            .unwrap();
+    }
+
+    #[test]
+    fn entry_for_a_free_inode() {
+        let img = populated("fsck-dangling");
+        let problems = check_after(&img, |fs| {
+            let id = id_of(fs, "/d/e");
+            fs.inode_bitmap.free(id as usize);
+        });
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("\"e\" points at free inode")));
+    }
+
+    #[test]
+    fn wrong_link_count() {
+        let img = populated("fsck-links");
+        let problems = check_after(&img, |fs| {
+            let id = id_of(fs, "/f");
+            let mut f = fs.get_inode_by_id(id);
+            f.hard_links = 1;
+            fs.save_inode(id, f);
+        });
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("has 1 links but 2 entries"));
+    }
+
+    #[test]
+    fn dotdot_pointing_elsewhere() {
+        let img = populated("fsck-dotdot");
+        let problems = check_after(&img, |fs| {
+            let id = id_of(fs, "/d/e");
+            fs.set_dotdot(id, 1).unwrap();
+        });
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("is \"..\" for inode 1, not \"..\"")));
+        // the links of both directories are off by one now
+        assert!(problems.iter().any(|p| p.contains("inode 1 has")));
+    }
+
+    #[test]
+    fn block_in_use_marked_free() {
+        let img = populated("fsck-unmarked");
+        let problems = check_after(&img, |fs| {
+            let d = fs.get_inode_by_id(id_of(fs, "/d"));
+            let b = fs.bmap(&d, 0);
+            fs.blocks_bitmap.free(b as usize);
+        });
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("is marked free"));
+    }
+
+    #[test]
+    fn blocks_past_the_size() {
+        let img = populated("fsck-size");
+        let problems = check_after(&img, |fs| {
+            let id = id_of(fs, "/f");
+            let mut f = fs.get_inode_by_id(id);
+            f.size = 0;
+            fs.save_inode(id, f);
+        });
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("has blocks past its size"));
+
+        let img = populated("fsck-prealloc");
+        img.mount(|fs| {
+            fs.fallocate(c"/f", 1, 0, 10000).unwrap();
+            fs.unmount().unwrap();
+        });
+        assert!(img.open(|fs| fs.check()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,543 @@
+use super::{block_p, inode_p, inode_t, Error, FileSystem, INODE_INDEXED};
+
+/// Index blocks start with the levels of index below them and the number of
+/// `(hash, block)` entries that follow, sorted by hash. At depth 0 the blocks
+/// are logical blocks of the directory (leaves), above it index blocks.
+const INDEX_HEADER: usize = 8;
+
+/// FNV-1a. It decides which leaf an entry lives in, so it must never change.
+fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn dentry_size(name_len: usize) -> usize {
+    8 + name_len.next_multiple_of(4)
+}
+
+fn encode_dentry(inode_num: inode_p, name: &[u8]) -> Vec<u8> {
+    let mut dentry = Vec::with_capacity(dentry_size(name.len()));
+    dentry.extend_from_slice(&inode_num.to_le_bytes());
+    dentry.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    dentry.extend_from_slice(name);
+    dentry.resize(dentry_size(name.len()), 0);
+    dentry
+}
+
+fn read_index(block: &[u8]) -> (u32, Vec<(u32, u32)>) {
+    let word = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+    let count = word(4) as usize;
+    let entries = (0..count)
+        .map(|i| {
+            let at = INDEX_HEADER + i * 8;
+            (word(at), word(at + 4))
+        })
+        .collect();
+    (word(0), entries)
+}
+
+fn write_index(block: &mut [u8], depth: u32, entries: &[(u32, u32)]) {
+    block.fill(0);
+    block[0..4].copy_from_slice(&depth.to_le_bytes());
+    block[4..8].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (i, (hash, b)) in entries.iter().enumerate() {
+        let at = INDEX_HEADER + i * 8;
+        block[at..at + 4].copy_from_slice(&hash.to_le_bytes());
+        block[at + 4..at + 8].copy_from_slice(&b.to_le_bytes());
+    }
+}
+
+/// Entry of a leaf being rebuilt: name hash, inode number and name.
+type LeafEntry = (u32, inode_p, Vec<u8>);
+
+impl<'a> FileSystem<'a> {
+    fn index_capacity(&self) -> usize {
+        (self.dir_block_room() - INDEX_HEADER) / 8
+    }
+
+    fn put_index(&mut self, b: block_p, depth: u32, entries: &[(u32, u32)]) {
+        write_index(self.get_data_block_mut(b), depth, entries);
+        self.seal_dir_block(b);
+    }
+
+    /// Collects the leaves below index block `block` that may hold names
+    /// hashing to `hash`. Entry 0 of a node covers everything below the next
+    /// one, and a leaf split on a hash collision shares its first hash with
+    /// the one before it, so a range includes both of its bounds.
+    fn index_leaves(&self, block: block_p, hash: u32, leaves: &mut Vec<u32>) {
+        let Some(block) = self.dir_block(block) else {
+            return;
+        };
+        let (depth, entries) = read_index(block);
+        for (i, (start, b)) in entries.iter().enumerate() {
+            if i > 0 && *start > hash {
+                break;
+            }
+            if entries.get(i + 1).is_some_and(|(next, _)| *next < hash) {
+                continue;
+            }
+            if depth == 0 {
+                leaves.push(*b);
+            } else {
+                self.index_leaves(*b, hash, leaves);
+            }
+        }
+    }
+
+    /// Index blocks from the root down to the leaf a new entry hashing to
+    /// `hash` goes to, with the position of the entry followed in each.
+    /// `None` if one of them fails its checksum.
+    fn index_path(&self, root: block_p, hash: u32) -> Option<(Vec<(block_p, usize)>, u32)> {
+        let mut path = vec![];
+        let mut b = root;
+        loop {
+            let (depth, entries) = read_index(self.dir_block(b)?);
+            if entries.is_empty() {
+                return None;
+            }
+            let pos = (1..entries.len())
+                .rev()
+                .find(|i| entries[*i].0 <= hash)
+                .unwrap_or(0);
+            path.push((b, pos));
+            if depth == 0 {
+                return Some((path, entries[pos].1));
+            }
+            b = entries[pos].1;
+        }
+    }
+
+    /// Inode number of `name` in the indexed directory `node`, reading only
+    /// the leaves its hash can be in. "." and ".." stay in the first block.
+    pub(super) fn index_lookup(&self, node: &inode_t, name: &str) -> Option<inode_p> {
+        let mut leaves = vec![];
+        if name == "." || name == ".." {
+            leaves.push(0);
+        } else {
+            self.index_leaves(node.rdev, name_hash(name.as_bytes()), &mut leaves);
+        }
+        for n in leaves {
+            let Some(mut data) = self.dir_block(self.bmap(node, n as usize)) else {
+                continue;
+            };
+            while let Some(dentry) = self.dentry(data) {
+                if dentry.name == name {
+                    return Some(dentry.inode_num);
+                }
+                data = &data[dentry.size..];
+            }
+        }
+        None
+    }
+
+    /// Clears the entry for `name` from its leaf, returns whether there was one.
+    pub(super) fn index_remove(&mut self, node: &inode_t, name: &str) -> bool {
+        let mut leaves = vec![];
+        self.index_leaves(node.rdev, name_hash(name.as_bytes()), &mut leaves);
+        for n in leaves {
+            let b = self.bmap(node, n as usize);
+            if self.dir_block(b).is_none() {
+                continue;
+            }
+            let mut i = 0;
+            while let Some(dentry) = self.dentry(&self.get_data_block(b)[i..]) {
+                let size = dentry.size;
+                if dentry.name == name {
+                    self.get_data_block_mut(b)[i..i + size].fill(0);
+                    self.seal_dir_block(b);
+                    return true;
+                }
+                i += size;
+            }
+        }
+        false
+    }
+
+    fn leaf_entries(&self, node: &inode_t, n: u32) -> Option<Vec<LeafEntry>> {
+        let mut entries = vec![];
+        let mut data = self.dir_block(self.bmap(node, n as usize))?;
+        while let Some(dentry) = self.dentry(data) {
+            let name = dentry.name.as_bytes().to_vec();
+            entries.push((name_hash(&name), dentry.inode_num, name));
+            data = &data[dentry.size..];
+        }
+        Some(entries)
+    }
+
+    /// Packs `entries` at the start of leaf `n`, which is already mapped.
+    fn write_leaf(&mut self, node: &inode_t, n: u32, entries: &[LeafEntry]) {
+        let b = self.bmap(node, n as usize);
+        let block = self.get_data_block_mut(b);
+        block.fill(0);
+        let mut at = 0;
+        for (_, inode_num, name) in entries {
+            let dentry = encode_dentry(*inode_num, name);
+            block[at..at + dentry.len()].copy_from_slice(&dentry);
+            at += dentry.len();
+        }
+        self.seal_dir_block(b);
+    }
+
+    /// Where to split a full leaf so both halves fit in a block, as close to
+    /// the middle as possible and between two different hashes if it can be.
+    fn leaf_split(&self, entries: &[LeafEntry]) -> Option<usize> {
+        let bs = self.dir_block_room();
+        let sizes: Vec<usize> = entries.iter().map(|e| dentry_size(e.2.len())).collect();
+        let total: usize = sizes.iter().sum();
+        let mut prefix = 0;
+        let mut best: Option<(bool, usize, usize)> = None;
+        for k in 1..entries.len() {
+            prefix += sizes[k - 1];
+            if prefix > bs || total - prefix > bs {
+                continue;
+            }
+            // prefer splitting between hashes, then balance
+            let key = (
+                entries[k - 1].0 == entries[k].0,
+                prefix.abs_diff(total / 2),
+                k,
+            );
+            if best.is_none_or(|b| key < b) {
+                best = Some(key);
+            }
+        }
+        best.map(|(_, _, k)| k)
+    }
+
+    /// Adds `(hash, child)` to the index right after the entry `path` ends
+    /// at, splitting the nodes that overflow. The root stays in place: when
+    /// it is full its entries move one level down.
+    fn index_insert(
+        &mut self,
+        path: &[(block_p, usize)],
+        hash: u32,
+        child: u32,
+    ) -> Result<(), Error> {
+        let cap = self.index_capacity();
+        let full = path
+            .iter()
+            .rev()
+            .take_while(|(b, _)| read_index(self.get_data_block(*b)).1.len() >= cap)
+            .count();
+        let needed = full + (full == path.len()) as usize;
+        // take every block up front so a full image can't leave half a split
+        let mut spare = vec![];
+        for _ in 0..needed {
+            match self.alloc_zeroed_block() {
+                Ok(b) => spare.push(b),
+                Err(e) => {
+                    for b in spare {
+                        self.blocks_bitmap.free(b as usize);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut path = path.to_vec();
+        if full == path.len() {
+            let (root, pos) = path[0];
+            let (depth, entries) = read_index(self.get_data_block(root));
+            let b = spare.pop().unwrap();
+            self.put_index(b, depth, &entries);
+            self.put_index(root, depth + 1, &[(0, b)]);
+            path[0] = (b, pos);
+            path.insert(0, (root, 0));
+        }
+        let (mut hash, mut child) = (hash, child);
+        for (b, pos) in path.into_iter().rev() {
+            let (depth, mut entries) = read_index(self.get_data_block(b));
+            entries.insert(pos + 1, (hash, child));
+            if entries.len() <= cap {
+                self.put_index(b, depth, &entries);
+                return Ok(());
+            }
+            let right = entries.split_off(entries.len() / 2);
+            let new = spare.pop().unwrap();
+            self.put_index(b, depth, &entries);
+            self.put_index(new, depth, &right);
+            (hash, child) = (right[0].0, new);
+        }
+        unreachable!("the root always has room after growing");
+    }
+
+    /// Adds an entry to the indexed directory `id`, splitting its leaf into a
+    /// new block at the end of the directory when it is full.
+    pub(super) fn index_add(
+        &mut self,
+        id: inode_p,
+        inode_num: inode_p,
+        name: &[u8],
+    ) -> Result<(), Error> {
+        let bs = self.sb.block_size as usize;
+        let node = self.get_inode_by_id(id);
+        let hash = name_hash(name);
+        let (path, leaf) = self
+            .index_path(node.rdev, hash)
+            .ok_or(Error::Io("checksum mismatch"))?;
+        let b = self.bmap(&node, leaf as usize);
+        let block = self.dir_block(b).ok_or(Error::Io("checksum mismatch"))?;
+        if let Some(at) = self.find_space_for_dentry(block, name.len() + 8) {
+            let dentry = encode_dentry(inode_num, name);
+            self.get_data_block_mut(b)[at..at + dentry.len()].copy_from_slice(&dentry);
+            self.seal_dir_block(b);
+            return Ok(());
+        }
+
+        let mut entries = self
+            .leaf_entries(&node, leaf)
+            .ok_or(Error::Io("checksum mismatch"))?;
+        // a mangled entry would be dropped with the rest of the leaf
+        self.check_io()?;
+        entries.push((hash, inode_num, name.to_vec()));
+        entries.sort_by_key(|e| e.0);
+        let split = self.leaf_split(&entries).ok_or(Error::NoSpace)?;
+        let new_leaf = (node.size as usize / bs) as u32;
+        self.bmap_alloc(id, new_leaf as usize)?;
+        if let Err(e) = self.index_insert(&path, entries[split].0, new_leaf) {
+            // the end of a file is always given back
+            let _ = self.free_range(id, new_leaf as usize, new_leaf as usize + 1);
+            return Err(e);
+        }
+        let mut node = self.get_inode_by_id(id);
+        node.size = (new_leaf as usize + 1) as u64 * bs as u64;
+        self.save_inode(id, node);
+        self.write_leaf(&node, leaf, &entries[..split]);
+        self.write_leaf(&node, new_leaf, &entries[split..]);
+        Ok(())
+    }
+
+    /// Turns the linear directory `id` into an indexed one: its entries get
+    /// sorted by hash into leaves after the first block, which keeps only "."
+    /// and "..". Nothing changes if there aren't enough free blocks.
+    pub(super) fn build_index(&mut self, id: inode_p) -> Result<(), Error> {
+        let bs = self.sb.block_size as usize;
+        let room = self.dir_block_room();
+        let cap = self.index_capacity();
+        let node = self.get_inode_by_id(id);
+        let data = self.get_dir_data(&node);
+        let old_blocks = data.len() / bs;
+
+        let mut entries = vec![];
+        let mut rest = &data[..];
+        while let Some(dentry) = self.dentry(rest) {
+            if dentry.name != "." && dentry.name != ".." {
+                let name = dentry.name.as_bytes().to_vec();
+                entries.push((name_hash(&name), dentry.inode_num, name));
+            }
+            rest = &rest[dentry.size..];
+        }
+        self.check_io()?;
+        entries.sort_by_key(|e| e.0);
+        let mut leaves: Vec<Vec<LeafEntry>> = vec![vec![]];
+        let mut used = 0;
+        for e in entries {
+            let size = dentry_size(e.2.len());
+            if used + size > room {
+                leaves.push(vec![]);
+                used = 0;
+            }
+            used += size;
+            leaves.last_mut().unwrap().push(e);
+        }
+
+        let mut index_blocks = 1;
+        let mut level = leaves.len();
+        while level > cap {
+            level = level.div_ceil(cap);
+            index_blocks += level;
+        }
+        let mut spare = vec![];
+        let mut failed = None;
+        for _ in 0..index_blocks {
+            match self.alloc_zeroed_block() {
+                Ok(b) => spare.push(b),
+                Err(e) => failed = Some(e),
+            }
+        }
+        for n in old_blocks..=leaves.len() {
+            if failed.is_none() {
+                failed = self.bmap_alloc(id, n).err();
+            }
+        }
+        if let Some(e) = failed {
+            for b in spare {
+                self.blocks_bitmap.free(b as usize);
+            }
+            let _ = self.free_range(id, old_blocks, usize::MAX);
+            return Err(e);
+        }
+
+        let node = self.get_inode_by_id(id);
+        // "." and ".." take the first 24 bytes of the first block
+        let first = self.bmap(&node, 0);
+        self.get_data_block_mut(first)[24..].fill(0);
+        self.seal_dir_block(first);
+        let mut keys = vec![];
+        for (i, leaf) in leaves.iter().enumerate() {
+            let n = i as u32 + 1;
+            self.write_leaf(&node, n, leaf);
+            keys.push((if i == 0 { 0 } else { leaf[0].0 }, n));
+        }
+        self.free_range(id, leaves.len() + 1, usize::MAX)?;
+
+        let mut depth = 0;
+        while keys.len() > cap {
+            keys = keys
+                .chunks(cap)
+                .map(|chunk| {
+                    let b = spare.pop().unwrap();
+                    self.put_index(b, depth, chunk);
+                    (chunk[0].0, b)
+                })
+                .collect();
+            depth += 1;
+        }
+        let root = spare.pop().unwrap();
+        self.put_index(root, depth, &keys);
+
+        let mut node = self.get_inode_by_id(id);
+        node.flags |= INODE_INDEXED;
+        node.rdev = root;
+        node.size = ((leaves.len() + 1) * bs) as u64;
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    fn free_index_block(&mut self, block: block_p) {
+        // whatever a bad block points to is left for fsck
+        let (depth, entries) = match self.dir_block(block) {
+            Some(data) => read_index(data),
+            None => (0, vec![]),
+        };
+        if depth > 0 {
+            for (_, b) in entries {
+                self.free_index_block(b);
+            }
+        }
+        self.blocks_bitmap.free(block as usize);
+    }
+
+    /// Releases the index of a directory that is being deleted.
+    pub(super) fn free_index(&mut self, node: &mut inode_t) {
+        if node.flags & INODE_INDEXED != 0 {
+            self.free_index_block(node.rdev);
+            node.flags &= !INODE_INDEXED;
+            node.rdev = 0;
+        }
+    }
+
+    fn count_index_blocks(&self, block: block_p) -> usize {
+        let Some(data) = self.dir_block(block) else {
+            return 1;
+        };
+        let (depth, entries) = read_index(data);
+        if depth == 0 {
+            return 1;
+        }
+        1 + entries
+            .iter()
+            .map(|(_, b)| self.count_index_blocks(*b))
+            .sum::<usize>()
+    }
+
+    /// Index blocks of `node`, which aren't part of its data.
+    pub(super) fn count_index(&self, node: &inode_t) -> usize {
+        if node.flags & INODE_INDEXED == 0 {
+            return 0;
+        }
+        self.count_index_blocks(node.rdev)
+    }
+
+    fn visit_index_blocks(&self, block: block_p, visit: &mut dyn FnMut(block_p) -> bool) {
+        if !visit(block) {
+            return;
+        }
+        let Some(data) = self.dir_block(block) else {
+            return;
+        };
+        let (depth, entries) = read_index(data);
+        if depth == 0 {
+            return;
+        }
+        for (_, b) in entries {
+            self.visit_index_blocks(b, visit);
+        }
+    }
+
+    /// Calls `visit` on every index block of `node`, reading on from those
+    /// it returns true for.
+    pub(super) fn visit_index(&self, node: &inode_t, visit: &mut dyn FnMut(block_p) -> bool) {
+        if node.flags & INODE_INDEXED != 0 {
+            self.visit_index_blocks(node.rdev, visit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::types::testing::Image;
+
+    fn path(i: usize) -> String {
+        format!("/big/a-fairly-long-file-name-to-fill-leaves-{i}")
+    }
+
+    #[test]
+    fn large_directory_gets_an_index() {
+        let img = Image::format("htree", 1024, 8000, 4096);
+        let files = 3000;
+        img.mount(|fs| {
+            fs.create_directory(c"/big", 0, 0).unwrap();
+            for i in 0..files {
+                let p = CString::new(path(i)).unwrap();
+                fs.create_file(&p, &[], 0x8000 | 0o644, 0, 0).unwrap();
+            }
+            fs.unmount().unwrap();
+        });
+        img.mount(|fs| {
+            let (node, _) = fs.find_file_mut("/big").unwrap();
+            assert_ne!(node.flags & INODE_INDEXED, 0);
+            // enough leaves for the root to point at index blocks
+            let (depth, _) = read_index(fs.get_data_block(node.rdev));
+            assert!(depth > 0);
+            for i in 0..files {
+                assert!(fs.find_file(&path(i)).is_some(), "{}", path(i));
+            }
+            assert!(fs.find_file("/big/missing").is_none());
+            assert_eq!(fs.get_files_in_dir(c"/big").len(), files + 2);
+
+            for i in (0..files).step_by(2) {
+                fs.unlink_file(&path(i)).unwrap();
+            }
+            for i in 0..files {
+                assert_eq!(fs.find_file(&path(i)).is_some(), i % 2 == 1);
+            }
+            assert!(fs.check().is_empty());
+            fs.unmount().unwrap();
+        });
+    }
+
+    #[test]
+    fn removing_an_indexed_directory_frees_the_index() {
+        let img = Image::format("htree-rmdir", 1024, 8000, 1024);
+        img.mount(|fs| {
+            let free = fs.blocks_bitmap.free;
+            fs.create_directory(c"/big", 0, 0).unwrap();
+            for i in 0..500 {
+                let p = CString::new(path(i)).unwrap();
+                fs.create_file(&p, &[], 0x8000 | 0o644, 0, 0).unwrap();
+            }
+            assert!(fs.count_index(&fs.find_file("/big").unwrap()) > 0);
+            for i in 0..500 {
+                fs.unlink_file(&path(i)).unwrap();
+            }
+            fs.unlink_dir(c"/big").unwrap();
+            assert_eq!(fs.blocks_bitmap.free, free);
+            assert!(fs.check().is_empty());
+        });
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::BTreeSet,
+    ops::Range,
+    os::unix::fs::FileExt,
+    time::{Duration, Instant},
+};
+
+use memmap2::UncheckedAdvice;
+
+use super::{
+    block_p, crc32c, superblock_t, Error, FileSystem, FEATURE_JOURNAL, SB_SIZE, STATE_CLEAN,
+};
+
+/// The journal starts with a header block: magic and length in blocks. The
+/// last transaction committed follows it as descriptor blocks, each with
+/// magic, sequence number, count and the image blocks whose copies come
+/// right after it, then a commit block: magic, sequence number and the CRC
+/// of everything from the first descriptor on.
+const JOURNAL_MAGIC: u32 = 0x4c4e_524a;
+const DESCRIPTOR_MAGIC: u32 = 0x4353_444a;
+const COMMIT_MAGIC: u32 = 0x544d_434a;
+const DESCRIPTOR_HEADER: usize = 12;
+
+/// Smallest journal worth having and the largest one `format` makes.
+const JOURNAL_MIN: u64 = 8;
+const JOURNAL_MAX: u64 = 4096;
+
+/// Longest a transaction stays open before `end_op` commits it.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+/// File data a transaction holds before `end_op` commits it, it only lives
+/// in memory until then.
+const COMMIT_DATA_MAX: usize = 32 << 20;
+
+fn get_u32(block: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(block[at..at + 4].try_into().unwrap())
+}
+
+fn put_u32(block: &mut [u8], at: usize, value: u32) {
+    block[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Blocks `format` sets aside for the journal out of `data_blocks`, 0 when
+/// the image is too small for one.
+fn journal_size(data_blocks: u64) -> u64 {
+    let blocks = (data_blocks / 16).min(JOURNAL_MAX);
+    if blocks < JOURNAL_MIN {
+        0
+    } else {
+        blocks
+    }
+}
+
+/// Metadata changed since the last commit. The mapping is private, nothing
+/// reaches the image file before `commit` writes it.
+#[derive(Debug, Default)]
+pub struct Journal {
+    /// First data block of the journal and its length, 0 without one.
+    start: block_p,
+    blocks: u32,
+    /// Of the next transaction.
+    sequence: u32,
+    /// Image blocks where the inode table and the data area start.
+    pub(super) inode_table: u64,
+    pub(super) data_area: u64,
+    /// Image blocks holding metadata that changed.
+    meta: BTreeSet<u64>,
+    /// Data blocks holding file contents that changed. They are written in
+    /// place before the metadata pointing at them is logged.
+    data: BTreeSet<block_p>,
+    opened: Option<Instant>,
+}
+
+impl Journal {
+    /// Lays out the journal of the image in `data` and, if it wasn't
+    /// cleanly unmounted, puts back the last transaction logged there. The
+    /// blocks it restored are part of the first transaction.
+    pub(super) fn open(data: &mut [u8]) -> Result<Self, Error> {
+        let sb_data: [u8; SB_SIZE] = data[0..SB_SIZE].try_into().unwrap();
+        let sb: superblock_t = zerocopy::transmute!(sb_data);
+        let bs = sb.block_size as usize;
+        let (inodes_id, _, first_block_id) = FileSystem::layout(&sb);
+        let mut journal = Journal {
+            inode_table: inodes_id,
+            data_area: first_block_id,
+            ..Default::default()
+        };
+        if sb.feature_incompat & FEATURE_JOURNAL == 0 {
+            return Ok(journal);
+        }
+        let data_blocks = sb.blocks_num as u64 - first_block_id;
+        if sb.journal_block < 2 || sb.journal_block as u64 + JOURNAL_MIN > data_blocks {
+            return Err(Error::Invalid("journal outside the data area"));
+        }
+        let start = (first_block_id + sb.journal_block as u64) as usize * bs;
+        let header = &data[start..start + bs];
+        let blocks = get_u32(header, 4);
+        if get_u32(header, 0) != JOURNAL_MAGIC {
+            return Err(Error::Io("journal header is corrupt"));
+        }
+        if (blocks as u64) < JOURNAL_MIN || sb.journal_block as u64 + blocks as u64 > data_blocks {
+            return Err(Error::Invalid("journal runs past the data area"));
+        }
+        journal.start = sb.journal_block;
+        journal.blocks = blocks;
+
+        let log = start + bs..start + blocks as usize * bs;
+        if get_u32(&data[log.start..], 0) == DESCRIPTOR_MAGIC {
+            journal.sequence = get_u32(&data[log.start..], 4).wrapping_add(1);
+        }
+        if sb.state & STATE_CLEAN != 0 {
+            return Ok(journal);
+        }
+        let seed = crc32c(0, &sb.uuid);
+        let Some(copies) = parse_log(&data[log.clone()], bs, seed) else {
+            return Ok(journal);
+        };
+        if copies.iter().any(|(n, _)| *n >= sb.blocks_num as u64) {
+            return Err(Error::Io("journal refers to blocks past the image"));
+        }
+        for (n, at) in copies {
+            let from = log.start + at;
+            data.copy_within(from..from + bs, n as usize * bs);
+            journal.touch(n);
+        }
+        eprintln!("replayed {} blocks from the journal", journal.meta.len());
+        Ok(journal)
+    }
+
+    /// Data blocks taken by the journal.
+    pub(super) fn area(&self) -> Range<block_p> {
+        self.start..self.start + self.blocks
+    }
+
+    /// Image block `n`, holding metadata, changed.
+    pub(super) fn touch(&mut self, n: u64) {
+        self.meta.insert(n);
+        self.opened.get_or_insert_with(Instant::now);
+    }
+
+    /// Data block `b`, holding file contents, changed.
+    pub(super) fn touch_data(&mut self, b: block_p) {
+        self.data.insert(b);
+        self.opened.get_or_insert_with(Instant::now);
+    }
+}
+
+/// Image blocks of the transaction in `log` along with where their copies
+/// are, `None` unless it was committed in full.
+fn parse_log(log: &[u8], bs: usize, seed: u32) -> Option<Vec<(u64, usize)>> {
+    let sequence = get_u32(log, 4);
+    let mut copies = vec![];
+    let mut at = 0;
+    loop {
+        let block = log.get(at..at + bs)?;
+        if get_u32(block, 4) != sequence {
+            return None;
+        }
+        match get_u32(block, 0) {
+            DESCRIPTOR_MAGIC => {
+                let count = get_u32(block, 8) as usize;
+                if count > (bs - DESCRIPTOR_HEADER) / 4 {
+                    return None;
+                }
+                for i in 0..count {
+                    let n = get_u32(block, DESCRIPTOR_HEADER + i * 4);
+                    copies.push((n as u64, at + (i + 1) * bs));
+                }
+                at += (count + 1) * bs;
+            }
+            COMMIT_MAGIC if get_u32(block, 8) == crc32c(seed, &log[..at]) => {
+                return Some(copies);
+            }
+            _ => return None,
+        }
+    }
+}
+
+impl<'a> FileSystem<'a> {
+    /// Sets aside the blocks after the root directory for the journal, if
+    /// the image is big enough for one.
+    pub(super) fn create_journal(&mut self) {
+        let blocks = journal_size(self.blocks_bitmap.size as u64);
+        if blocks == 0 {
+            return;
+        }
+        let start: block_p = 2;
+        for b in start..start + blocks as block_p {
+            self.blocks_bitmap.take(b as usize);
+        }
+        let header = self.get_data_block_mut(start);
+        header.fill(0);
+        put_u32(header, 0, JOURNAL_MAGIC);
+        put_u32(header, 4, blocks as u32);
+        self.sb.feature_incompat |= FEATURE_JOURNAL;
+        self.sb.journal_block = start;
+        self.journal.start = start;
+        self.journal.blocks = blocks as u32;
+    }
+
+    /// Image block `n` as it stands in the mapping.
+    fn image_block(&self, n: u64) -> &[u8] {
+        let bs = self.sb.block_size as u64;
+        let (_, blocks_bitmap_id, _) = Self::layout(&self.sb);
+        let (area, first): (&[u8], u64) = if n >= self.journal.data_area {
+            (self.data_blocks, self.journal.data_area)
+        } else if n >= blocks_bitmap_id {
+            (self.blocks_bitmap.data, blocks_bitmap_id)
+        } else if n >= self.journal.inode_table {
+            (self.inodes, self.journal.inode_table)
+        } else if n >= 1 {
+            (self.inode_bitmap.data, 1)
+        } else {
+            (self.data, 0)
+        };
+        let offset = ((n - first) * bs) as usize;
+        &area[offset..offset + bs as usize]
+    }
+
+    /// The running transaction as it goes into the journal, `None` if it
+    /// doesn't fit.
+    fn log_record(&self, meta: &BTreeSet<u64>) -> Option<Vec<u8>> {
+        let bs = self.sb.block_size as usize;
+        let per_descriptor = (bs - DESCRIPTOR_HEADER) / 4;
+        let blocks = meta.len().div_ceil(per_descriptor) + meta.len() + 1;
+        // the header takes the first block
+        if blocks >= self.journal.blocks as usize {
+            return None;
+        }
+        let sequence = self.journal.sequence;
+        let meta: Vec<u64> = meta.iter().copied().collect();
+        let mut log = Vec::with_capacity(blocks * bs);
+        for chunk in meta.chunks(per_descriptor) {
+            let mut descriptor = vec![0; bs];
+            put_u32(&mut descriptor, 0, DESCRIPTOR_MAGIC);
+            put_u32(&mut descriptor, 4, sequence);
+            put_u32(&mut descriptor, 8, chunk.len() as u32);
+            for (i, n) in chunk.iter().enumerate() {
+                put_u32(&mut descriptor, DESCRIPTOR_HEADER + i * 4, *n as u32);
+            }
+            log.extend_from_slice(&descriptor);
+            for n in chunk {
+                log.extend_from_slice(self.image_block(*n));
+            }
+        }
+        let mut commit = vec![0; bs];
+        put_u32(&mut commit, 0, COMMIT_MAGIC);
+        put_u32(&mut commit, 4, sequence);
+        put_u32(&mut commit, 8, crc32c(crc32c(0, &self.sb.uuid), &log));
+        log.extend_from_slice(&commit);
+        Some(log)
+    }
+
+    /// Writes the running transaction to the image file: file contents in
+    /// place, then the metadata to the journal and, once that is on disk,
+    /// where it belongs. A crash at any point leaves either the last
+    /// transaction or this one for `rs_init` to find. One too big for the
+    /// journal clears it and goes straight into place, unprotected.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        let Some(file) = self.image else {
+            return Ok(());
+        };
+        let bs = self.sb.block_size as u64;
+        let (_, blocks_bitmap_id, _) = Self::layout(&self.sb);
+        let data_area = self.journal.data_area;
+        let mut meta = self.journal.meta.clone();
+        meta.extend(self.inode_bitmap.dirty.iter().map(|n| 1 + *n as u64));
+        meta.extend(
+            self.blocks_bitmap
+                .dirty
+                .iter()
+                .map(|n| blocks_bitmap_id + *n as u64),
+        );
+        let mut data = vec![];
+        for &b in &self.journal.data {
+            if self.blocks_bitmap.freed.contains(&(b as usize)) {
+                // the last transaction may still have it holding metadata,
+                // which a crash before this one is logged mustn't lose
+                meta.insert(data_area + b as u64);
+            } else if self.blocks_bitmap.is_set(b as usize)
+                && !meta.contains(&(data_area + b as u64))
+            {
+                data.push(data_area + b as u64);
+            }
+        }
+        if meta.is_empty() && data.is_empty() {
+            return Ok(());
+        }
+
+        for n in data {
+            file.write_all_at(self.image_block(n), n * bs)
+                .map_err(|_| Error::Io("failed to write file data"))?;
+        }
+        if self.journal.blocks != 0 {
+            let at = (data_area + self.journal.start as u64 + 1) * bs;
+            let log = match self.log_record(&meta) {
+                Some(log) => log,
+                None => {
+                    // replaying the last transaction over this one would
+                    // roll it back, so that one goes first
+                    eprintln!("transaction too big for the journal, writing it in place");
+                    vec![0; bs as usize]
+                }
+            };
+            file.write_all_at(&log, at)
+                .and_then(|_| file.sync_data())
+                .map_err(|_| Error::Io("failed to write journal"))?;
+        }
+        for &n in &meta {
+            file.write_all_at(self.image_block(n), n * bs)
+                .map_err(|_| Error::Io("failed to write metadata"))?;
+        }
+        file.sync_data()
+            .map_err(|_| Error::Io("failed to sync image"))?;
+
+        self.journal.sequence = self.journal.sequence.wrapping_add(1);
+        self.journal.meta.clear();
+        self.journal.data.clear();
+        self.journal.opened = None;
+        for bitmap in [&mut self.inode_bitmap, &mut self.blocks_bitmap] {
+            bitmap.dirty.clear();
+            bitmap.freed.clear();
+        }
+        if let Some(map) = self.map {
+            // every page written to is back in the file now, dropping the
+            // private copies only gives the memory back
+            let _ = map.unchecked_advise_range(UncheckedAdvice::DontNeed, 0, map.len());
+        }
+        Ok(())
+    }
+
+    /// Commits the running transaction once it has been open for
+    /// `COMMIT_INTERVAL` or grows big. Called after every operation, and
+    /// from `commit_timer` for an idle mount.
+    pub fn end_op(&mut self) -> Result<(), Error> {
+        let Some(opened) = self.journal.opened else {
+            return Ok(());
+        };
+        let meta = self.journal.meta.len()
+            + self.inode_bitmap.dirty.len()
+            + self.blocks_bitmap.dirty.len();
+        if opened.elapsed() >= COMMIT_INTERVAL
+            || (self.journal.blocks != 0 && meta > self.journal.blocks as usize / 4)
+            || self.journal.data.len() * self.sb.block_size as usize > COMMIT_DATA_MAX
+        {
+            return self.commit();
+        }
+        Ok(())
+    }
+
+    /// `end_op` for a timer: commits the running transaction if it is due
+    /// and returns how long until the next one can be.
+    pub fn commit_timer(&mut self) -> Result<Duration, Error> {
+        self.end_op()?;
+        Ok(self.journal.opened.map_or(COMMIT_INTERVAL, |opened| {
+            COMMIT_INTERVAL.saturating_sub(opened.elapsed())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::types::testing::Image;
+
+    /// Image offset of the block holding inode `id`.
+    fn inode_block(fs: &FileSystem, id: u32) -> u64 {
+        let bs = fs.sb.block_size as u64;
+        (fs.journal.inode_table + id as u64 * 128 / bs) * bs
+    }
+
+    /// Creates "/f" and commits it, then wipes its inode where it belongs
+    /// and crashes. Returns the offsets of that block and of the journal.
+    fn crash_after_create(img: &Image) -> (u64, u64) {
+        let (block, log) = img.mount(|fs| {
+            fs.create_file(c"/f", b"hello", 0x8000 | 0o644, 0, 0)
+                .unwrap();
+            fs.commit().unwrap();
+            let id = fs.lookup(c"/f").unwrap();
+            let bs = fs.sb.block_size as u64;
+            let log = (fs.journal.data_area + fs.journal.start as u64 + 1) * bs;
+            (inode_block(fs, id), log)
+        });
+        img.write(block, &[0; 1024]);
+        (block, log)
+    }
+
+    #[test]
+    fn replay_puts_back_the_last_transaction() {
+        let img = Image::format("replay", 1024, 2000, 256);
+        crash_after_create(&img);
+        img.mount(|fs| {
+            assert!(fs.lookup(c"/f").is_some());
+            assert!(!fs.take_io_error());
+        });
+    }
+
+    #[test]
+    fn torn_commit_is_not_replayed() {
+        let img = Image::format("torn", 1024, 2000, 256);
+        let (block, log) = crash_after_create(&img);
+        let count = get_u32(&img.read(log, 12), 8) as u64;
+        let commit = log + (count + 1) * 1024;
+        assert_eq!(get_u32(&img.read(commit, 4), 0), COMMIT_MAGIC);
+        let mut crc = img.read(commit + 8, 4);
+        crc[0] ^= 1;
+        img.write(commit + 8, &crc);
+        img.open(|_| {}).unwrap();
+        assert_eq!(img.read(block, 1024), vec![0; 1024]);
+    }
+
+    #[test]
+    fn timer_commits_an_idle_transaction() {
+        let img = Image::format("timer", 1024, 2000, 256);
+        img.mount(|fs| {
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            fs.end_op().unwrap();
+            let left = fs.commit_timer().unwrap();
+            assert!(left > Duration::ZERO && left <= COMMIT_INTERVAL);
+            assert!(fs.journal.opened.is_some());
+            // no other operation comes along
+            fs.journal.opened = Some(Instant::now() - COMMIT_INTERVAL);
+            assert_eq!(fs.commit_timer().unwrap(), COMMIT_INTERVAL);
+            assert!(fs.journal.opened.is_none());
+        });
+        img.mount(|fs| assert!(fs.lookup(c"/f").is_some()));
+    }
+
+    #[test]
+    fn overflow_clears_the_journal_first() {
+        let img = Image::format("overflow", 1024, 300, 256);
+        img.mount(|fs| {
+            fs.create_file(c"/old", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            fs.commit().unwrap();
+            fs.unlink_file("/old").unwrap();
+            for i in 0..150 {
+                let path = CString::new(format!("/f{i}")).unwrap();
+                fs.create_file(&path, &[], 0x8000 | 0o644, 0, 0).unwrap();
+            }
+            assert!(fs.log_record(&fs.journal.meta).is_none());
+            fs.commit().unwrap();
+        });
+        // the crash leaves the image dirty, the creation of "/old" mustn't
+        // be replayed over what followed
+        img.mount(|fs| {
+            assert!(fs.lookup(c"/old").is_none());
+            for i in 0..150 {
+                let path = CString::new(format!("/f{i}")).unwrap();
+                assert!(fs.lookup(&path).is_some());
+            }
+            assert!(!fs.take_io_error());
+        });
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    process,
+};
+
+use memmap2::MmapOptions;
+
+use super::{superblock_t, Error, FileSystem, SB_MAGIC, SB_SIZE};
+
+/// Image file in the temp directory for a test, removed when it is dropped.
+pub(super) struct Image {
+    path: PathBuf,
+}
+
+impl Image {
+    /// Formats a fresh image the way `rs_init_and_format` does, owned by
+    /// root.
+    pub(super) fn format(name: &str, block_size: u32, blocks: u32, inodes: u32) -> Self {
+        let path = std::env::temp_dir().join(format!("fs_rust-{}-{name}", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(block_size as u64 * blocks as u64).unwrap();
+        let mut map = unsafe { MmapOptions::new().map_copy(&file).unwrap() };
+        let sb_data: [u8; SB_SIZE] = map[0..SB_SIZE].try_into().unwrap();
+        let mut sb: superblock_t = zerocopy::transmute!(sb_data);
+        sb.header = SB_MAGIC;
+        sb.block_size = block_size;
+        sb.blocks_num = blocks;
+        sb.inodes_num = inodes;
+        let d: [u8; SB_SIZE] = zerocopy::transmute!(sb);
+        map[..SB_SIZE].copy_from_slice(&d);
+        let map: *mut _ = &mut map;
+        let mut fs = FileSystem::new(unsafe { &mut (&mut *map)[..] }).unwrap();
+        fs.set_image(&file, unsafe { &*map });
+        fs.format(0, 0).unwrap();
+        Image { path }
+    }
+
+    /// Hands the image to `f` as `FileSystem::new` finds it, replaying the
+    /// journal but not mounting it. Nothing `f` doesn't commit reaches the
+    /// file, returning without `unmount` is a crash.
+    pub(super) fn open<T>(&self, f: impl FnOnce(&mut FileSystem) -> T) -> Result<T, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .unwrap();
+        let mut map = unsafe { MmapOptions::new().map_copy(&file).unwrap() };
+        // the file system keeps both the mapping and a view of it, as in
+        // the bindings
+        let map: *mut _ = &mut map;
+        let mut fs = FileSystem::new(unsafe { &mut (&mut *map)[..] })?;
+        fs.set_image(&file, unsafe { &*map });
+        Ok(f(&mut fs))
+    }
+
+    /// `open` followed by `mount`, as `rs_init` does.
+    pub(super) fn mount<T>(&self, f: impl FnOnce(&mut FileSystem) -> T) -> T {
+        self.open(|fs| {
+            fs.mount().unwrap();
+            f(fs)
+        })
+        .unwrap()
+    }
+
+    pub(super) fn read(&self, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        File::open(&self.path)
+            .unwrap()
+            .read_exact_at(&mut buf, offset)
+            .unwrap();
+        buf
+    }
+
+    pub(super) fn write(&self, offset: u64, data: &[u8]) {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .unwrap()
+            .write_all_at(data, offset)
+            .unwrap();
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
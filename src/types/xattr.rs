@@ -0,0 +1,251 @@
+use std::ffi::CStr;
+
+use zerocopy::{FromZeros, IntoBytes};
+
+use super::{
+    acl::{acl_is_minimal, acl_mode, parse_acl, ACL_ACCESS, ACL_DEFAULT},
+    inode_p, inode_t, Error, FileSystem,
+};
+
+/// Size of the attribute area kept inside the inode. The device number and
+/// the xattr block pointer took their share of the 24 spare bytes first, so
+/// only a single short attribute fits, up to 8 bytes of name and value
+/// together after the header; anything more goes to the xattr block.
+pub const XATTR_INLINE_SIZE: usize = 12;
+/// First word of every xattr block.
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+/// Name prefixes are stored as a one byte index in front of the rest of the name.
+const XATTR_PREFIXES: [(u8, &str); 4] = [
+    (1, "user."),
+    (2, "trusted."),
+    (3, "security."),
+    (4, "system."),
+];
+
+/// Entry header: prefix index, name length and 16-bit value length.
+const XATTR_ENTRY_HEADER: usize = 4;
+
+fn split_name(name: &str) -> Option<(u8, &str)> {
+    XATTR_PREFIXES.iter().find_map(|(index, prefix)| {
+        name.strip_prefix(prefix)
+            .filter(|rest| !rest.is_empty() && rest.len() <= u8::MAX as usize)
+            .map(|rest| (*index, rest))
+    })
+}
+
+/// Decodes the entries stored in an inline area or xattr block, stopping at
+/// the first zero prefix index.
+fn parse_entries(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut attrs = vec![];
+    while data.len() >= XATTR_ENTRY_HEADER && data[0] != 0 {
+        let name_len = data[1] as usize;
+        let value_len = u16::from_le_bytes(data[2..4].try_into().unwrap()) as usize;
+        let end = XATTR_ENTRY_HEADER + name_len + value_len;
+        if data.len() < end {
+            break;
+        }
+        let Some((_, prefix)) = XATTR_PREFIXES.iter().find(|(index, _)| *index == data[0]) else {
+            break;
+        };
+        let name =
+            String::from_utf8_lossy(&data[XATTR_ENTRY_HEADER..XATTR_ENTRY_HEADER + name_len]);
+        attrs.push((
+            format!("{prefix}{name}"),
+            data[XATTR_ENTRY_HEADER + name_len..end].to_vec(),
+        ));
+        data = &data[end..];
+    }
+    attrs
+}
+
+fn serialize_entries(attrs: &[(String, Vec<u8>)]) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    for (name, value) in attrs {
+        let (index, name) =
+            split_name(name).ok_or(Error::Unsupported("unsupported attribute namespace"))?;
+        let value_len = u16::try_from(value.len()).map_err(|_| Error::NoSpace)?;
+        data.push(index);
+        data.push(name.len() as u8);
+        data.extend_from_slice(&value_len.to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(value);
+    }
+    Ok(data)
+}
+
+impl<'a> FileSystem<'a> {
+    pub(super) fn read_xattrs(&self, node: &inode_t) -> Vec<(String, Vec<u8>)> {
+        if node.xattr_block != 0 {
+            let block = self.get_data_block(node.xattr_block);
+            if u32::from_le_bytes(block[..4].try_into().unwrap()) != XATTR_MAGIC {
+                return vec![];
+            }
+            return parse_entries(&block[4..]);
+        }
+        parse_entries(node.xattr_inline.as_bytes())
+    }
+
+    /// Stores the whole attribute set inline if it fits, otherwise in the
+    /// inode's xattr block, and saves the inode.
+    pub(super) fn write_xattrs(
+        &mut self,
+        id: inode_p,
+        mut node: inode_t,
+        attrs: &[(String, Vec<u8>)],
+    ) -> Result<(), Error> {
+        let data = serialize_entries(attrs)?;
+        node.xattr_inline.zero();
+        if data.len() <= XATTR_INLINE_SIZE {
+            if node.xattr_block != 0 {
+                self.blocks_bitmap.free(node.xattr_block as usize);
+                node.xattr_block = 0;
+            }
+            node.xattr_inline.as_mut_bytes()[..data.len()].copy_from_slice(&data);
+        } else {
+            if data.len() + 4 > self.sb.block_size as usize {
+                return Err(Error::NoSpace);
+            }
+            if node.xattr_block == 0 {
+                node.xattr_block = self.blocks_bitmap.allocate().ok_or(Error::NoSpace)? as u32;
+            }
+            let block = self.get_data_block_mut(node.xattr_block);
+            block.zero();
+            block[..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+            block[4..4 + data.len()].copy_from_slice(&data);
+        }
+        node.touch_changed();
+        self.save_inode(id, node);
+        Ok(())
+    }
+
+    /// Releases the xattr block of an inode that is being deleted.
+    pub(super) fn free_xattrs(&mut self, node: &mut inode_t) {
+        if node.xattr_block != 0 {
+            self.blocks_bitmap.free(node.xattr_block as usize);
+            node.xattr_block = 0;
+        }
+        node.xattr_inline.zero();
+    }
+
+    pub fn get_xattr(&self, path: &CStr, name: &CStr) -> Result<Vec<u8>, Error> {
+        let name = name
+            .to_str()
+            .map_err(|_| Error::Invalid("bad attribute name"))?;
+        if let Some(node) = self.find_file(path.to_str().expect("path should be UTF-8")) {
+            return self
+                .read_xattrs(&node)
+                .into_iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value)
+                .ok_or(Error::NoAttribute);
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn set_xattr(&mut self, path: &CStr, name: &CStr, value: &[u8]) -> Result<(), Error> {
+        let name = name
+            .to_str()
+            .map_err(|_| Error::Invalid("bad attribute name"))?;
+        if split_name(name).is_none() {
+            return Err(Error::Unsupported("unsupported attribute namespace"));
+        }
+        if let Some((mut node, id)) =
+            self.find_file_mut(path.to_str().expect("path should be UTF-8"))
+        {
+            let mut attrs = self.read_xattrs(&node);
+            if name == ACL_ACCESS || name == ACL_DEFAULT {
+                let acl = parse_acl(value).ok_or(Error::Invalid("invalid acl"))?;
+                if name == ACL_DEFAULT && !node.is_directory() {
+                    return Err(Error::Invalid("default acl on a non-directory"));
+                }
+                if name == ACL_ACCESS {
+                    // the mode mirrors the owner, group class and other entries
+                    node.type_perm = node.type_perm & !0o777 | acl_mode(&acl);
+                    if acl_is_minimal(&acl) {
+                        attrs.retain(|(n, _)| n != name);
+                        return self.write_xattrs(id, node, &attrs);
+                    }
+                }
+            }
+            if let Some((_, v)) = attrs.iter_mut().find(|(n, _)| n == name) {
+                *v = value.to_vec();
+            } else {
+                attrs.push((name.to_string(), value.to_vec()));
+            }
+            return self.write_xattrs(id, node, &attrs);
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Returns the attribute names, each terminated by a nul byte.
+    pub fn list_xattr(&self, path: &CStr) -> Result<Vec<u8>, Error> {
+        if let Some(node) = self.find_file(path.to_str().expect("path should be UTF-8")) {
+            let mut list = vec![];
+            for (name, _) in self.read_xattrs(&node) {
+                list.extend_from_slice(name.as_bytes());
+                list.push(0);
+            }
+            return Ok(list);
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn remove_xattr(&mut self, path: &CStr, name: &CStr) -> Result<(), Error> {
+        let name = name
+            .to_str()
+            .map_err(|_| Error::Invalid("bad attribute name"))?;
+        if let Some((node, id)) = self.find_file_mut(path.to_str().expect("path should be UTF-8")) {
+            let mut attrs = self.read_xattrs(&node);
+            let len = attrs.len();
+            attrs.retain(|(n, _)| n != name);
+            if attrs.len() == len {
+                return Err(Error::NoAttribute);
+            }
+            return self.write_xattrs(id, node, &attrs);
+        }
+        Err(Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::testing::Image;
+
+    fn node(fs: &FileSystem) -> inode_t {
+        fs.find_file_mut("/f").unwrap().0
+    }
+
+    #[test]
+    fn attributes_spill_to_the_xattr_block() {
+        let img = Image::format("xattr-spill", 1024, 2000, 256);
+        img.mount(|fs| {
+            fs.create_file(c"/f", &[], 0x8000 | 0o644, 0, 0).unwrap();
+            let free = fs.blocks_bitmap.free;
+            // 4 bytes of header, 3 of name and 5 of value fill it up
+            fs.set_xattr(c"/f", c"user.abc", b"12345").unwrap();
+            assert_eq!(node(fs).xattr_block, 0);
+            assert_eq!(fs.blocks_bitmap.free, free);
+
+            fs.set_xattr(c"/f", c"user.abc", b"123456").unwrap();
+            assert_ne!(node(fs).xattr_block, 0);
+            assert_eq!(fs.blocks_bitmap.free, free - 1);
+            fs.set_xattr(c"/f", c"user.x", b"1").unwrap();
+            fs.unmount().unwrap();
+        });
+        img.mount(|fs| {
+            assert_eq!(fs.get_xattr(c"/f", c"user.abc").unwrap(), b"123456");
+            assert_eq!(fs.get_xattr(c"/f", c"user.x").unwrap(), b"1");
+            assert_eq!(fs.list_xattr(c"/f").unwrap(), b"user.abc\0user.x\0");
+
+            // back to what fits inline, the block is given back
+            let free = fs.blocks_bitmap.free;
+            fs.remove_xattr(c"/f", c"user.abc").unwrap();
+            assert_eq!(node(fs).xattr_block, 0);
+            assert_eq!(fs.blocks_bitmap.free, free + 1);
+            assert_eq!(fs.get_xattr(c"/f", c"user.x").unwrap(), b"1");
+            assert_eq!(fs.get_xattr(c"/f", c"user.abc"), Err(Error::NoAttribute));
+        });
+    }
+}